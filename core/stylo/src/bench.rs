@@ -0,0 +1,169 @@
+//! `stylo bench`: local socket reception and database write throughput.
+//!
+//! Datagrams queued on one end of a socket pair are read from the other the
+//! way the daemon reads `/run/log.sock`, timestamps, parsing and the
+//! lookup of the sender in `/proc` (see cred.rs) included, but without
+//! writing to the database. The run is done
+//! twice, taking a single datagram per system call (as stylo did before
+//! `recvmmsg`) and in batches, so both figures come from the same machine.
+//!
+//! `stylo bench --write` measures the other half: entries are written to a
+//! scratch database next to the real one, so on the same filesystem and
+//! with the configured `durability`, once with a transaction per entry (as
+//! one-shot calls and stylo before group commit do) and once in batches of
+//! `batch_size` as the daemon's writer does.
+
+use crate::boot;
+use crate::config::{Config, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::cred;
+use crate::datagram::{self, Datagrams};
+use crate::db;
+use crate::entry::Entry;
+use crate::schema;
+use crate::sink;
+use rusqlite::{Connection, Result};
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::{Duration, Instant};
+
+const DEFAULT_COUNT: usize = 200_000;
+/// Entries per write run; a synced commit per entry is slow
+const DEFAULT_WRITE_COUNT: usize = 5_000;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let (write, args) = match args.split_first() {
+        Some((flag, rest)) if flag == "--write" => (true, rest),
+        _ => (false, args),
+    };
+    let count = match args {
+        [] if write => DEFAULT_WRITE_COUNT,
+        [] => DEFAULT_COUNT,
+        [count] => count.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid count: {}", count);
+            process::exit(1);
+        }),
+        _ => {
+            crate::print_usage();
+            process::exit(1);
+        }
+    };
+    if write {
+        return run_write(count);
+    }
+
+    let cfg = Config::load();
+    println!("Receiving {} datagrams per run", count);
+    let single = measure(count, 1, &cfg);
+    println!("  1 per call:         {:>9.0} msg/s", single);
+    let batched = measure(count, crate::RECV_BATCH, &cfg);
+    println!(
+        "  up to {:<2} per call: {:>9.0} msg/s ({:.1}x)",
+        crate::RECV_BATCH,
+        batched,
+        batched / single
+    );
+    Ok(())
+}
+
+/// Messages per second received and parsed, `batch` per system call.
+/// The socket queue is filled up front and only draining it is timed, as
+/// during a burst that arrives faster than stylo reads.
+fn measure(count: usize, batch: usize, cfg: &Config) -> f64 {
+    let (receiver, sender) =
+        UnixDatagram::pair().unwrap_or_else(|e| panic!("Could not create socket pair: {}", e));
+    datagram::enable_passcred(&receiver).unwrap_or_else(|e| panic!("SO_PASSCRED: {}", e));
+    datagram::enable_timestamps(&receiver).unwrap_or_else(|e| panic!("SO_TIMESTAMP: {}", e));
+    let _ = sender.set_nonblocking(true);
+    let _ = receiver.set_nonblocking(true);
+
+    let mut datagrams = Datagrams::new(batch, DEFAULT_MAX_MESSAGE_SIZE);
+    let mut lookups = cred::Lookups::default();
+    let (mut sent, mut received) = (0, 0);
+    let mut elapsed = Duration::ZERO;
+    while received < count {
+        while sent < count {
+            let message = format!("bench INFO benchmark message number {}", sent);
+            match sender.send(message.as_bytes()) {
+                Ok(_) => sent += 1,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("Socket write error: {}", e),
+            }
+        }
+
+        let start = Instant::now();
+        loop {
+            match datagrams.recv(&receiver) {
+                Ok(n) => received += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("Socket read error: {}", e),
+            }
+            lookups.clear();
+            for datagram in datagrams.iter() {
+                let mut entry = Entry::parse_received(datagram.data, datagram.size);
+                entry.received_usec = datagram.received_usec;
+                let _ = cred::attribute(&mut entry, datagram.creds, cfg, &mut lookups);
+                entry.stamp_received();
+            }
+        }
+        elapsed += start.elapsed();
+    }
+    count as f64 / elapsed.as_secs_f64()
+}
+
+fn run_write(count: usize) -> Result<()> {
+    let cfg = Config::load();
+    let path = format!("{}.bench", db::get_db_path());
+    let batch_size = cfg.batch_size();
+    println!(
+        "Writing {} entries per run (durability {})",
+        count,
+        format!("{:?}", cfg.durability).to_lowercase()
+    );
+    let result = (|| {
+        let single = measure_writes(&path, &cfg, count, 1)?;
+        println!("  {:<20}{:>9.0} entries/s", "1 per commit:", single);
+        let batched = measure_writes(&path, &cfg, count, batch_size)?;
+        println!(
+            "  {:<20}{:>9.0} entries/s ({:.1}x)",
+            format!("{} per commit:", batch_size),
+            batched,
+            batched / single
+        );
+        Ok(())
+    })();
+    remove_scratch(&path);
+    result
+}
+
+/// Entries per second written to a fresh database at `path`, `batch` per
+/// transaction.
+fn measure_writes(path: &str, cfg: &Config, count: usize, batch: usize) -> Result<f64> {
+    remove_scratch(path);
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    schema::migrate(&conn)?;
+    boot::register(&conn)?;
+    sink::set_durability(&conn, cfg.durability)?;
+
+    let entries: Vec<Entry> = (0..count)
+        .map(|i| {
+            let mut entry = Entry::new("bench", "INFO", &format!("benchmark message number {}", i));
+            entry.stamp_received();
+            entry
+        })
+        .collect();
+    let start = Instant::now();
+    for chunk in entries.chunks(batch) {
+        sink::write(&conn, chunk, &[])?;
+    }
+    Ok(count as f64 / start.elapsed().as_secs_f64())
+}
+
+fn remove_scratch(path: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", path, suffix));
+    }
+}
@@ -0,0 +1,108 @@
+//! Boot sessions.
+//!
+//! StyxOS runs from a ramdisk and reboots often, so entries are grouped by
+//! the kernel's boot id. Each boot is recorded once in `boots` with the
+//! kernel release, the wall time it started and the OS from `os-release`;
+//! every entry references its boot in `logs.boot`. The `boot_list` view
+//! numbers boots like `journalctl -b`: 0 is the current one, -1 the one
+//! before.
+
+use crate::clock;
+use rusqlite::{Connection, Result, params};
+use std::fs;
+use std::sync::LazyLock;
+
+pub struct Boot {
+    /// `/proc/sys/kernel/random/boot_id`, empty if unavailable
+    pub id: String,
+    pub kernel: Option<String>,
+    /// Wall-clock time of the boot in microseconds
+    pub started_usec: i64,
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+}
+
+static CURRENT: LazyLock<Boot> = LazyLock::new(Boot::read);
+
+pub fn get_boot_id_path() -> String {
+    if cfg!(debug_assertions) {
+        std::env::var("STYLO_BOOT_ID")
+            .unwrap_or_else(|_| "/proc/sys/kernel/random/boot_id".to_string())
+    } else {
+        "/proc/sys/kernel/random/boot_id".to_string()
+    }
+}
+
+/// The boot this process runs in, read once.
+pub fn current() -> &'static Boot {
+    &CURRENT
+}
+
+impl Boot {
+    fn read() -> Boot {
+        let os_release = fs::read_to_string("/etc/os-release")
+            .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+            .unwrap_or_default();
+        let os_value = |key: &str| {
+            os_release.lines().find_map(|line| {
+                let value = line.strip_prefix(key)?.strip_prefix('=')?;
+                Some(value.trim_matches('"').to_string())
+            })
+        };
+
+        Boot {
+            id: fs::read_to_string(get_boot_id_path())
+                .map(|id| id.trim().to_string())
+                .unwrap_or_default(),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .ok()
+                .map(|release| release.trim().to_string()),
+            started_usec: clock::realtime_usec() - clock::boottime_usec(),
+            os_name: os_value("PRETTY_NAME").or_else(|| os_value("NAME")),
+            os_version: os_value("VERSION_ID"),
+        }
+    }
+}
+
+/// Record the current boot unless it is known already.
+pub fn register(conn: &Connection) -> Result<()> {
+    let boot = current();
+    conn.execute(
+        "INSERT INTO boots (boot_id, started_usec, kernel, os_name, os_version)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(boot_id) DO NOTHING",
+        params![
+            boot.id,
+            boot.started_usec,
+            boot.kernel,
+            boot.os_name,
+            boot.os_version
+        ],
+    )?;
+    Ok(())
+}
+
+/// `stylo boots`: list the recorded boots, newest last.
+pub fn run_list() -> Result<()> {
+    let conn = crate::db::init_db()?;
+    let mut stmt = conn.prepare(
+        "SELECT offset, boot_id, datetime(started_usec / 1000000, 'unixepoch'),
+                kernel, os_name, entries
+         FROM boot_list ORDER BY offset",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(format!(
+            "{:>4} {} {} {} {} ({} entries)",
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            row.get::<_, i64>(5)?,
+        ))
+    })?;
+    for row in rows {
+        println!("{}", row?);
+    }
+    Ok(())
+}
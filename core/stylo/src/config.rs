@@ -0,0 +1,410 @@
+//! Daemon configuration, read from a flat `key = value` file in the same
+//! format as `charon.conf`. Missing keys keep their defaults, so an absent
+//! file yields a daemon that only serves the local sockets.
+
+use crate::severity;
+use regex_lite::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Largest message stored in full unless `max_message_size` says otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// How long an incomplete multi-line entry waits for more lines
+pub const DEFAULT_MULTILINE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Entries written in one transaction at most
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// How long the first entry of a batch waits for others
+pub const DEFAULT_BATCH_LATENCY: Duration = Duration::from_millis(50);
+
+/// Entries waiting for the writer at most
+pub const DEFAULT_QUEUE_SIZE: usize = 10_000;
+
+/// Share of its filesystem the database may take
+pub const DEFAULT_MAX_DB_SIZE: SizeLimit = SizeLimit::Percent(50);
+
+/// Client certificate policy of the TLS listener
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Verify a certificate if the client presents one
+    Optional,
+    /// Reject clients without a certificate signed by `tls_client_ca`
+    Required,
+}
+
+/// How the claimed `source` of local datagrams relates to the sender
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SourcePolicy {
+    /// Keep whatever the sender claims
+    #[default]
+    Off,
+    /// Replace the source with the name of the sending binary
+    Stamp,
+    /// Reject entries whose source does not match the sending binary
+    Verify,
+}
+
+/// Which lines of a source continue the entry before them
+#[derive(Debug, Clone)]
+pub enum Continuation {
+    /// Lines starting with a space or tab
+    Indent,
+    /// Lines not matching the pattern that starts a new entry
+    Start(Regex),
+}
+
+/// A log file followed by the file input, see tail.rs
+#[derive(Debug, Clone)]
+pub struct TailFile {
+    pub path: String,
+    pub source: String,
+    pub severity: String,
+}
+
+/// What happens to entries whose severity is not one of the RFC 5424
+/// levels or a known alias, see severity.rs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum UnknownSeverity {
+    /// Store the severity as given, without a `level`
+    #[default]
+    Keep,
+    /// Store the entry with this level instead
+    Map(u8),
+    /// Drop the entry and log the rejection
+    Reject,
+}
+
+/// When a committed batch is on disk, see sink.rs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Every commit is synced; survives power loss
+    #[default]
+    Full,
+    /// Synced at WAL checkpoints; survives a crash of stylo, the last
+    /// commits may be lost on power loss
+    Normal,
+    /// Never synced, left to the kernel
+    Off,
+}
+
+/// Which entry is dropped when the writer's queue is full, see sink.rs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest queued entry
+    Oldest,
+    /// Drop the entry that does not fit
+    Newest,
+    /// Drop the oldest of the least severe entries, or the new one if
+    /// nothing queued is less severe
+    #[default]
+    Severity,
+}
+
+/// Largest size of the database, see quota.rs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeLimit {
+    Off,
+    Bytes(u64),
+    /// Percentage of the size of the filesystem holding the database
+    Percent(u64),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Address for the RFC 5426 UDP syslog listener (off when unset)
+    pub udp_listen: Option<SocketAddr>,
+    /// Address for the RFC 6587 TCP syslog listener (off when unset)
+    pub tcp_listen: Option<SocketAddr>,
+    /// Address for the RFC 5425 TLS syslog listener (off when unset)
+    pub tls_listen: Option<SocketAddr>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_auth: ClientAuth,
+    pub tls_client_ca: Option<String>,
+    /// `host:port` of a collector that receives every entry over TLS
+    pub tls_forward: Option<String>,
+    pub tls_forward_ca: Option<String>,
+    pub tls_forward_cert: Option<String>,
+    pub tls_forward_key: Option<String>,
+    pub source_policy: SourcePolicy,
+    /// Executables allowed to claim a source besides the one of that name,
+    /// from `source_exe.SOURCE` keys
+    pub source_exes: HashMap<String, Vec<String>>,
+    pub unknown_severity: UnknownSeverity,
+    /// cgroup below which crun creates container cgroups (empty means `/`)
+    pub container_cgroup_root: String,
+    /// Bytes kept of a single message; longer ones are stored truncated
+    pub max_message_size: Option<usize>,
+    /// Continuation rules per source, see multiline.rs
+    pub multiline: HashMap<String, Continuation>,
+    pub multiline_timeout: Option<Duration>,
+    /// Files to follow, one `tail_file` key each
+    pub tail_files: Vec<TailFile>,
+    /// Keep the FTS5 index of messages, see search.rs
+    pub full_text_search: Option<bool>,
+    /// Daemon entries written per transaction at most
+    pub batch_size: Option<usize>,
+    pub batch_latency: Option<Duration>,
+    pub durability: Durability,
+    pub queue_size: Option<usize>,
+    pub overflow: Overflow,
+    /// `SO_RCVBUF` of the datagram sockets (kernel default when unset)
+    pub receive_buffer: Option<usize>,
+    pub max_db_size: Option<SizeLimit>,
+}
+
+pub fn get_config_path() -> String {
+    if cfg!(debug_assertions) {
+        std::env::var("STYLO_CONF").unwrap_or_else(|_| "stylo.conf".to_string())
+    } else {
+        "/etc/stylo/stylo.conf".to_string()
+    }
+}
+
+impl Config {
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn multiline_timeout(&self) -> Duration {
+        self.multiline_timeout.unwrap_or(DEFAULT_MULTILINE_TIMEOUT)
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE)
+    }
+
+    pub fn batch_latency(&self) -> Duration {
+        self.batch_latency.unwrap_or(DEFAULT_BATCH_LATENCY)
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE)
+    }
+
+    pub fn full_text_search(&self) -> bool {
+        self.full_text_search.unwrap_or(true)
+    }
+
+    pub fn max_db_size(&self) -> SizeLimit {
+        self.max_db_size.unwrap_or(DEFAULT_MAX_DB_SIZE)
+    }
+
+    pub fn load() -> Config {
+        let path = get_config_path();
+        match fs::read_to_string(&path) {
+            Ok(content) => Config::parse(&content),
+            Err(_) => Config::default(),
+        }
+    }
+
+    fn parse(content: &str) -> Config {
+        let mut cfg = Config::default();
+
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let Some((key, val)) = trimmed.split_once('=') else {
+                continue;
+            };
+            let key = key.trim();
+            let val = val.trim().trim_matches('"');
+
+            match key {
+                "udp_listen" => cfg.udp_listen = parse_addr(key, val),
+                "tcp_listen" => cfg.tcp_listen = parse_addr(key, val),
+                "tls_listen" => cfg.tls_listen = parse_addr(key, val),
+                "tls_cert" => cfg.tls_cert = parse_string(val),
+                "tls_key" => cfg.tls_key = parse_string(val),
+                "tls_client_ca" => cfg.tls_client_ca = parse_string(val),
+                "tls_client_auth" => {
+                    cfg.tls_client_auth = match val {
+                        "optional" => ClientAuth::Optional,
+                        "required" => ClientAuth::Required,
+                        _ => ClientAuth::None,
+                    }
+                }
+                "tls_forward" => cfg.tls_forward = parse_string(val),
+                "tls_forward_ca" => cfg.tls_forward_ca = parse_string(val),
+                "tls_forward_cert" => cfg.tls_forward_cert = parse_string(val),
+                "tls_forward_key" => cfg.tls_forward_key = parse_string(val),
+                "source_policy" => {
+                    cfg.source_policy = match val {
+                        "stamp" => SourcePolicy::Stamp,
+                        "verify" => SourcePolicy::Verify,
+                        _ => SourcePolicy::Off,
+                    }
+                }
+                "unknown_severity" => {
+                    cfg.unknown_severity = match val {
+                        "keep" => UnknownSeverity::Keep,
+                        "reject" => UnknownSeverity::Reject,
+                        level => match severity::level(level) {
+                            Some(level) => UnknownSeverity::Map(level),
+                            None => {
+                                eprintln!("Invalid value for {}: {}", key, val);
+                                UnknownSeverity::Keep
+                            }
+                        },
+                    }
+                }
+                "container_cgroup_root" => cfg.container_cgroup_root = val.to_string(),
+                "max_message_size" => cfg.max_message_size = parse_size(key, val),
+                "multiline_timeout_ms" => {
+                    cfg.multiline_timeout =
+                        parse_size(key, val).map(|ms| Duration::from_millis(ms as u64))
+                }
+                "tail_file" => cfg.tail_files.extend(parse_tail_file(key, val)),
+                "full_text_search" => cfg.full_text_search = parse_switch(key, val),
+                "batch_size" => cfg.batch_size = parse_size(key, val),
+                "batch_latency_ms" => {
+                    cfg.batch_latency =
+                        parse_size(key, val).map(|ms| Duration::from_millis(ms as u64))
+                }
+                "durability" => {
+                    cfg.durability = match val {
+                        "full" => Durability::Full,
+                        "normal" => Durability::Normal,
+                        "off" => Durability::Off,
+                        _ => {
+                            eprintln!("Invalid value for {}: {}", key, val);
+                            Durability::Full
+                        }
+                    }
+                }
+                "queue_size" => cfg.queue_size = parse_size(key, val),
+                "overflow" => {
+                    cfg.overflow = match val {
+                        "drop_oldest" => Overflow::Oldest,
+                        "drop_newest" => Overflow::Newest,
+                        "drop_severity" => Overflow::Severity,
+                        _ => {
+                            eprintln!("Invalid value for {}: {}", key, val);
+                            Overflow::Severity
+                        }
+                    }
+                }
+                "receive_buffer" => cfg.receive_buffer = parse_size(key, val),
+                "max_db_size" => cfg.max_db_size = parse_limit(key, val),
+                _ => {
+                    if let Some(source) = key.strip_prefix("multiline.") {
+                        if let Some(rule) = parse_continuation(key, val) {
+                            cfg.multiline.insert(source.to_string(), rule);
+                        }
+                    } else if let Some(source) = key.strip_prefix("source_exe.") {
+                        cfg.source_exes
+                            .entry(source.to_string())
+                            .or_default()
+                            .push(val.to_string());
+                    } else {
+                        eprintln!("Ignoring unknown config key: {}", key);
+                    }
+                }
+            }
+        }
+
+        cfg
+    }
+}
+
+fn parse_string(val: &str) -> Option<String> {
+    if val.is_empty() {
+        None
+    } else {
+        Some(val.to_string())
+    }
+}
+
+fn parse_addr(key: &str, val: &str) -> Option<SocketAddr> {
+    if val.is_empty() {
+        return None;
+    }
+    val.parse()
+        .map_err(|_| eprintln!("Invalid address for {}: {}", key, val))
+        .ok()
+}
+
+fn parse_size(key: &str, val: &str) -> Option<usize> {
+    val.parse().ok().filter(|size| *size > 0).or_else(|| {
+        eprintln!("Invalid size for {}: {}", key, val);
+        None
+    })
+}
+
+/// `off`, a percentage like `50%` or bytes with an optional `K`, `M` or
+/// `G` suffix.
+fn parse_limit(key: &str, val: &str) -> Option<SizeLimit> {
+    let limit = if val == "off" {
+        Some(SizeLimit::Off)
+    } else if let Some(percent) = val.strip_suffix('%') {
+        percent
+            .parse()
+            .ok()
+            .filter(|percent| (1..=100).contains(percent))
+            .map(SizeLimit::Percent)
+    } else {
+        let (number, unit) = match val.char_indices().last() {
+            Some((i, 'K')) => (&val[..i], 1 << 10),
+            Some((i, 'M')) => (&val[..i], 1 << 20),
+            Some((i, 'G')) => (&val[..i], 1 << 30),
+            _ => (val, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .filter(|n| *n > 0)
+            .and_then(|n| n.checked_mul(unit))
+            .map(SizeLimit::Bytes)
+    };
+    limit.or_else(|| {
+        eprintln!("Invalid value for {}: {}", key, val);
+        None
+    })
+}
+
+fn parse_switch(key: &str, val: &str) -> Option<bool> {
+    match val {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => {
+            eprintln!("Invalid value for {}: {}", key, val);
+            None
+        }
+    }
+}
+
+fn parse_continuation(key: &str, val: &str) -> Option<Continuation> {
+    if val == "indent" {
+        return Some(Continuation::Indent);
+    }
+    let Some(pattern) = val.strip_prefix("start:") else {
+        eprintln!("Invalid rule for {}: {}", key, val);
+        return None;
+    };
+    Regex::new(pattern)
+        .map_err(|e| eprintln!("Invalid pattern for {}: {}", key, e))
+        .ok()
+        .map(Continuation::Start)
+}
+
+/// `PATH SOURCE [SEVERITY]`, the severity defaulting to `INFO`
+fn parse_tail_file(key: &str, val: &str) -> Option<TailFile> {
+    let mut words = val.split_whitespace();
+    let (Some(path), Some(source)) = (words.next(), words.next()) else {
+        eprintln!("Invalid value for {}: {}", key, val);
+        return None;
+    };
+    Some(TailFile {
+        path: path.to_string(),
+        source: source.to_string(),
+        severity: words.next().unwrap_or("INFO").to_string(),
+    })
+}
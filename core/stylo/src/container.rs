@@ -0,0 +1,33 @@
+//! Container attribution from the sender's cgroup v2 path.
+//!
+//! crun places each container in a cgroup of its own: `/<id>` with the
+//! cgroupfs manager, `.../crun-<id>.scope` with the systemd manager. The
+//! container id is the name given to `crun run`, so it doubles as the
+//! container name. Scopes created by podman, docker and containerd are
+//! recognised as well.
+
+/// Scope prefixes of container managers using systemd-style cgroups
+const SCOPE_PREFIXES: [&str; 4] = ["crun-", "libpod-", "docker-", "cri-containerd-"];
+
+/// Map a cgroup path to the container it belongs to. `root` is the cgroup
+/// below which crun creates container cgroups (`/` unless the OCI configs
+/// set `cgroupsPath`).
+pub fn from_cgroup(cgroup: &str, root: &str) -> Option<String> {
+    // systemd-managed scopes can sit anywhere in the hierarchy
+    for component in cgroup.split('/') {
+        if let Some(unit) = component.strip_suffix(".scope")
+            && let Some(id) = SCOPE_PREFIXES.iter().find_map(|p| unit.strip_prefix(p))
+        {
+            return Some(id.to_string());
+        }
+    }
+
+    // cgroupfs: the first component below the root is the container id,
+    // deeper cgroups belong to processes inside the container
+    let below = cgroup.strip_prefix(root.trim_end_matches('/'))?;
+    let id = below.strip_prefix('/')?.split('/').next()?;
+    if id.is_empty() || id.ends_with(".slice") || id.ends_with(".scope") {
+        return None;
+    }
+    Some(id.to_string())
+}
@@ -0,0 +1,177 @@
+//! Sender credentials for the local sockets.
+//!
+//! With `SO_PASSCRED` enabled the kernel attaches the sending process's
+//! pid, uid and gid to every datagram. Those cannot be forged by
+//! unprivileged senders, so they are used to look up the sender binary in
+//! `/proc` and, depending on `source_policy`, to stamp or verify the
+//! claimed `source`. A source is genuine if it is the name of the sender's
+//! executable and that executable lives in one of `TRUSTED_DIRS`, or if
+//! `source_exe.SOURCE` allows that very executable to claim it (busybox
+//! applets, interpreters, programs installed elsewhere). The file name
+//! alone proves nothing: any user can copy a binary to `/tmp/x/charon`.
+//! A datagram without credentials is never genuine. Receiving them is up
+//! to datagram.rs.
+//!
+//! The `/proc` lookup costs three file reads, more than receiving and
+//! parsing the datagram. Datagrams received together usually come from
+//! few processes, so each process is looked up once per batch (`Lookups`);
+//! the next batch looks again, so a reused pid is never mistaken for the
+//! process it had before.
+
+use crate::config::{Config, SourcePolicy};
+use crate::container;
+use crate::entry::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Directories only root can write to, whose executables may claim their
+/// own name as source
+const TRUSTED_DIRS: [&str; 5] = ["/bin", "/sbin", "/usr/bin", "/usr/sbin", "/usr/libexec"];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What `/proc` tells about a sending process.
+#[derive(Clone)]
+struct Process {
+    comm: Option<String>,
+    exe: Option<String>,
+    cgroup: Option<String>,
+    container: Option<String>,
+}
+
+/// The processes looked up for one batch of datagrams, by pid.
+#[derive(Default)]
+pub struct Lookups(HashMap<i32, Process>);
+
+impl Lookups {
+    /// Forget the processes, before the next batch.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Record the sender and its container on `entry` and apply the source
+/// policy. Returns an explanation if the entry must be rejected.
+pub fn attribute(
+    entry: &mut Entry,
+    creds: Option<Credentials>,
+    cfg: &Config,
+    lookups: &mut Lookups,
+) -> Result<(), String> {
+    let Some(creds) = creds else {
+        return match cfg.source_policy {
+            SourcePolicy::Off => Ok(()),
+            SourcePolicy::Stamp => {
+                entry.source = "unknown".to_string();
+                Ok(())
+            }
+            SourcePolicy::Verify => Err(format!(
+                "Rejected entry without sender credentials claiming source '{}'",
+                entry.source
+            )),
+        };
+    };
+    let process = lookups
+        .0
+        .entry(creds.pid)
+        .or_insert_with(|| look_up(creds.pid, cfg))
+        .clone();
+    entry.pid = Some(creds.pid);
+    entry.uid = Some(creds.uid);
+    entry.gid = Some(creds.gid);
+    entry.comm = process.comm;
+    entry.exe = process.exe;
+    entry.cgroup = process.cgroup;
+    entry.container = process.container;
+
+    // Only the executable counts: any process can rename itself and so
+    // choose its `comm`. Outside the trusted directories the file name is
+    // as good as chosen, so such senders are named by their full path.
+    let binary = entry.exe.as_deref().map(|exe| {
+        let path = Path::new(exe);
+        let trusted = path
+            .parent()
+            .is_some_and(|dir| TRUSTED_DIRS.iter().any(|trusted| dir == Path::new(trusted)));
+        match path.file_name() {
+            Some(name) if trusted => name.to_string_lossy().to_string(),
+            _ => exe.to_string(),
+        }
+    });
+    let genuine = binary.as_deref() == Some(entry.source.as_str())
+        || entry.exe.as_ref().is_some_and(|exe| {
+            cfg.source_exes
+                .get(&entry.source)
+                .is_some_and(|allowed| allowed.contains(exe))
+        });
+
+    match cfg.source_policy {
+        SourcePolicy::Off => Ok(()),
+        SourcePolicy::Stamp => {
+            if !genuine {
+                entry.source = binary.unwrap_or_else(|| "unknown".to_string());
+            }
+            Ok(())
+        }
+        SourcePolicy::Verify => {
+            if genuine {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Rejected entry from pid {} ({}) claiming source '{}'",
+                    creds.pid,
+                    entry.exe.as_deref().unwrap_or("exited"),
+                    entry.source
+                ))
+            }
+        }
+    }
+}
+
+fn look_up(pid: i32, cfg: &Config) -> Process {
+    let proc_dir = format!("/proc/{}", pid);
+    let cgroup = fs::read_to_string(format!("{}/cgroup", proc_dir))
+        .ok()
+        .and_then(|cgroups| unified_cgroup(&cgroups));
+    Process {
+        comm: fs::read_to_string(format!("{}/comm", proc_dir))
+            .ok()
+            .map(|comm| comm.trim_end().to_string()),
+        exe: fs::read_link(format!("{}/exe", proc_dir)).ok().map(|exe| {
+            exe.to_string_lossy()
+                .trim_end_matches(" (deleted)")
+                .to_string()
+        }),
+        container: cgroup
+            .as_deref()
+            .and_then(|cgroup| container::from_cgroup(cgroup, &cfg.container_cgroup_root)),
+        cgroup,
+    }
+}
+
+/// The entry logged in place of a rejected one, attributed to the real
+/// sender so the attempt can be traced.
+pub fn rejection(rejected: &Entry, reason: &str) -> Entry {
+    let mut entry = Entry::new("stylo", "WARNING", reason);
+    entry.pid = rejected.pid;
+    entry.uid = rejected.uid;
+    entry.gid = rejected.gid;
+    entry.comm = rejected.comm.clone();
+    entry.exe = rejected.exe.clone();
+    entry.cgroup = rejected.cgroup.clone();
+    entry.container = rejected.container.clone();
+    entry
+}
+
+/// The cgroup v2 path is the `0::` line of `/proc/PID/cgroup`.
+fn unified_cgroup(cgroups: &str) -> Option<String> {
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_string)
+}
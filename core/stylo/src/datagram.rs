@@ -0,0 +1,288 @@
+//! Batched datagram reception for the local sockets and the UDP listener.
+//!
+//! Datagrams are received with `recvmmsg`, one system call for everything
+//! queued during a burst rather than one per message. The kernel attaches
+//! to each one the sender's credentials (`SO_PASSCRED`, local sockets
+//! only, see cred.rs), the time it arrived (`SO_TIMESTAMP`) and the number
+//! of datagrams the socket dropped so far because its receive buffer was
+//! full (`SO_RXQ_OVFL`). Growth of that counter is reported as lost
+//! messages.
+//!
+//! Senders can also attach file descriptors (`SCM_RIGHTS`), which the
+//! kernel installs into stylo's fd table. They are closed right away, or
+//! any local user could exhaust the daemon's descriptors.
+
+use crate::cred::Credentials;
+use crate::entry::Entry;
+use serde_json::Value;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// Control buffer per datagram; u64 elements keep it aligned for cmsghdr
+type Control = [u64; 16];
+
+pub fn enable_passcred(socket: &impl AsRawFd) -> io::Result<()> {
+    set_option(socket, libc::SO_PASSCRED, 1)
+}
+
+/// Have the kernel attach the wall-clock time each datagram arrived.
+pub fn enable_timestamps(socket: &impl AsRawFd) -> io::Result<()> {
+    set_option(socket, libc::SO_TIMESTAMP, 1)
+}
+
+/// Have the kernel attach its count of datagrams dropped on the socket.
+pub fn enable_drop_count(socket: &impl AsRawFd) -> io::Result<()> {
+    set_option(socket, libc::SO_RXQ_OVFL, 1)
+}
+
+/// Set the receive buffer size. `SO_RCVBUFFORCE` lets root go beyond
+/// `net.core.rmem_max`; everyone else gets at most that.
+pub fn set_receive_buffer(socket: &impl AsRawFd, size: usize) -> io::Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    set_option(socket, libc::SO_RCVBUFFORCE, size)
+        .or_else(|_| set_option(socket, libc::SO_RCVBUF, size))
+}
+
+fn set_option(socket: &impl AsRawFd, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// One datagram of a `Datagrams` batch.
+pub struct Datagram<'a> {
+    /// The part that fit into the buffer
+    pub data: &'a [u8],
+    /// Size of the whole datagram (`MSG_TRUNC`); larger than `data` if it
+    /// was cut off
+    pub size: usize,
+    /// Sender address of UDP datagrams
+    pub peer: Option<SocketAddr>,
+    pub creds: Option<Credentials>,
+    /// Arrival time from `SO_TIMESTAMP` in microseconds
+    pub received_usec: Option<i64>,
+    /// The control messages did not fit (`MSG_CTRUNC`), so the credentials
+    /// may be missing
+    pub control_truncated: bool,
+}
+
+/// What the kernel attached to a datagram.
+#[derive(Default, Clone, Copy)]
+struct Ancillary {
+    creds: Option<Credentials>,
+    received_usec: Option<i64>,
+    /// Datagrams dropped on the socket since it was created
+    drops: Option<u32>,
+    truncated: bool,
+}
+
+/// Buffers for receiving several datagrams with a single `recvmmsg` call.
+pub struct Datagrams {
+    buffers: Vec<Vec<u8>>,
+    names: Vec<libc::sockaddr_storage>,
+    // Only used through `headers`, which point into them
+    _controls: Vec<Control>,
+    _iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+    ancillary: Vec<Ancillary>,
+    count: usize,
+    /// The socket's drop counter as last seen
+    drops: u32,
+    lost: u32,
+}
+
+impl Datagrams {
+    /// Room for `capacity` datagrams of `max_size` bytes each.
+    pub fn new(capacity: usize, max_size: usize) -> Datagrams {
+        let mut buffers = vec![vec![0u8; max_size]; capacity];
+        let mut names = vec![unsafe { mem::zeroed::<libc::sockaddr_storage>() }; capacity];
+        let mut controls = vec![Control::default(); capacity];
+        // The headers point into the other vectors, none of which is
+        // resized after this
+        let mut iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let headers = iovecs
+            .iter_mut()
+            .zip(controls.iter_mut())
+            .zip(names.iter_mut())
+            .map(|((iov, control), name)| {
+                let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+                header.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+                header.msg_hdr.msg_iov = iov;
+                header.msg_hdr.msg_iovlen = 1;
+                header.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                header
+            })
+            .collect();
+        Datagrams {
+            buffers,
+            names,
+            _controls: controls,
+            _iovecs: iovecs,
+            headers,
+            ancillary: vec![Ancillary::default(); capacity],
+            count: 0,
+            drops: 0,
+            lost: 0,
+        }
+    }
+
+    /// Wait for a datagram and take whatever else is already queued, up to
+    /// the capacity. Returns the number received.
+    pub fn recv(&mut self, socket: &impl AsRawFd) -> io::Result<usize> {
+        self.count = 0;
+        self.lost = 0;
+        for header in &mut self.headers {
+            // The kernel shrinks both to what it used
+            header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            header.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+        }
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                self.headers.as_mut_ptr(),
+                self.headers.len() as _,
+                // Descriptors sent along are closed below; close-on-exec
+                // covers the moment in between
+                libc::MSG_TRUNC | libc::MSG_WAITFORONE | libc::MSG_CMSG_CLOEXEC,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.count = count as usize;
+
+        for (header, ancillary) in self.headers[..self.count].iter().zip(&mut self.ancillary) {
+            *ancillary = unsafe { read_ancillary(&header.msg_hdr) };
+            if let Some(drops) = ancillary.drops {
+                // The counter is cumulative and wraps around
+                self.lost = self.lost.wrapping_add(drops.wrapping_sub(self.drops));
+                self.drops = drops;
+            }
+        }
+        Ok(self.count)
+    }
+
+    /// Datagrams the kernel dropped before the last `recv`, since the one
+    /// before it.
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+
+    /// The datagrams of the last `recv`.
+    pub fn iter(&self) -> impl Iterator<Item = Datagram<'_>> {
+        self.headers[..self.count]
+            .iter()
+            .zip(&self.buffers)
+            .zip(&self.names)
+            .zip(&self.ancillary)
+            .map(|(((header, buf), name), ancillary)| {
+                let size = header.msg_len as usize;
+                Datagram {
+                    data: &buf[..size.min(buf.len())],
+                    size,
+                    peer: peer_addr(name, header.msg_hdr.msg_namelen),
+                    creds: ancillary.creds,
+                    received_usec: ancillary.received_usec,
+                    control_truncated: ancillary.truncated,
+                }
+            })
+    }
+}
+
+/// The entry reporting datagrams dropped on `socket`.
+pub fn lost_entry(socket: &str, lost: u32) -> Entry {
+    let mut entry = Entry::new(
+        "stylo",
+        "WARNING",
+        &format!("{} messages lost on {} (receive buffer full)", lost, socket),
+    );
+    entry.fields.push(("lost".to_string(), Value::from(lost)));
+    entry.counter = Some(("lost", lost as u64));
+    entry
+}
+
+/// Read the control messages of a received datagram, closing any file
+/// descriptors passed with it.
+unsafe fn read_ancillary(msg: &libc::msghdr) -> Ancillary {
+    let mut ancillary = Ancillary {
+        truncated: msg.msg_flags & libc::MSG_CTRUNC != 0,
+        ..Ancillary::default()
+    };
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_SOCKET, libc::SCM_CREDENTIALS) => {
+                    let ucred: libc::ucred = ptr::read_unaligned(data as *const libc::ucred);
+                    ancillary.creds = Some(Credentials {
+                        pid: ucred.pid,
+                        uid: ucred.uid,
+                        gid: ucred.gid,
+                    });
+                }
+                (libc::SOL_SOCKET, libc::SCM_TIMESTAMP) => {
+                    let tv: libc::timeval = ptr::read_unaligned(data as *const libc::timeval);
+                    ancillary.received_usec = Some(tv.tv_sec * 1_000_000 + tv.tv_usec);
+                }
+                (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                    ancillary.drops = Some(ptr::read_unaligned(data as *const u32));
+                }
+                (libc::SOL_SOCKET, libc::SCM_RIGHTS) => {
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for i in 0..len / mem::size_of::<libc::c_int>() {
+                        let fd = ptr::read_unaligned((data as *const libc::c_int).add(i));
+                        libc::close(fd);
+                    }
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+    ancillary
+}
+
+/// The sender of an IPv4 or IPv6 datagram; local sockets have none.
+fn peer_addr(name: &libc::sockaddr_storage, len: libc::socklen_t) -> Option<SocketAddr> {
+    let len = len as usize;
+    match name.ss_family as libc::c_int {
+        libc::AF_INET if len >= mem::size_of::<libc::sockaddr_in>() => {
+            let sin = unsafe { *(name as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 if len >= mem::size_of::<libc::sockaddr_in6>() => {
+            let sin6 = unsafe { *(name as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,134 @@
+use crate::boot;
+use crate::entry::{Entry, EventTime};
+use crate::schema;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, Result, Transaction, TransactionBehavior, named_params, params};
+use serde_json::Value;
+
+pub fn get_db_path() -> String {
+    if cfg!(debug_assertions) {
+        std::env::var("STYLO_DB").unwrap_or_else(|_| "log.db".to_string())
+    } else {
+        "/var/log.db".to_string()
+    }
+}
+
+pub fn init_db() -> Result<Connection> {
+    let conn = Connection::open(get_db_path())?;
+    // Set busy timeout to handle concurrent writes from oneshot calls
+    conn.pragma_update(None, "busy_timeout", "5000")?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    // Let deletes from logs clean up log_fields
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+
+    schema::migrate(&conn)?;
+    boot::register(&conn)?;
+    Ok(conn)
+}
+
+/// Write a single entry in its own transaction.
+pub fn insert_entry(conn: &Connection, entry: &Entry) -> Result<()> {
+    // Take the write lock up front: a deferred transaction that reads first
+    // (the full-text triggers do) fails at once instead of waiting when
+    // another connection committed in between.
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    insert(&tx, entry)?;
+    tx.commit()
+}
+
+/// Write an entry within the caller's transaction. The statements are
+/// cached, so batches only prepare them once.
+pub fn insert(conn: &Connection, entry: &Entry) -> Result<()> {
+    let (event_usec, text_time) = match &entry.event_time {
+        Some(EventTime::Usec(usec)) => (Some(*usec), None),
+        Some(EventTime::Text(text)) => (None, Some(text.as_str())),
+        None => (None, None),
+    };
+
+    conn.prepare_cached(
+        "INSERT INTO logs (timestamp, source, severity, message, facility,
+                           hostname, app_name, procid, msgid, structured_data,
+                           origin, peer_subject, kmsg_seq, monotonic_usec,
+                           pid, uid, gid, comm, exe, cgroup, container, stream,
+                           truncated, original_length, line_count, level,
+                           time_usec, received_usec, boottime_usec, boot, unsynced)
+         VALUES (COALESCE(datetime(:event_usec / 1000000, 'unixepoch'), datetime(:text_time),
+                          datetime(:received_usec / 1000000, 'unixepoch'), CURRENT_TIMESTAMP),
+                 :source, :severity, :message, :facility,
+                 :hostname, :app_name, :procid, :msgid, :structured_data,
+                 :origin, :peer_subject, :kmsg_seq, :monotonic_usec,
+                 :pid, :uid, :gid, :comm, :exe, :cgroup, :container, :stream,
+                 :truncated, :original_length, :line_count, :level,
+                 COALESCE(:event_usec,
+                          CAST(unixepoch(:text_time, 'subsec') * 1000000 AS INTEGER),
+                          :received_usec),
+                 :received_usec, :boottime_usec,
+                 (SELECT id FROM boots WHERE boot_id = :boot_id),
+                 (SELECT 1 FROM boots WHERE boot_id = :boot_id AND clock_synced_usec IS NULL))",
+    )?
+    .execute(named_params! {
+        ":event_usec": event_usec,
+        ":text_time": text_time,
+        ":source": entry.source,
+        ":severity": entry.severity,
+        ":message": entry.message,
+        ":facility": entry.facility,
+        ":hostname": entry.hostname,
+        ":app_name": entry.app_name,
+        ":procid": entry.procid,
+        ":msgid": entry.msgid,
+        ":structured_data": entry.structured_data,
+        ":origin": entry.origin,
+        ":peer_subject": entry.peer_subject,
+        ":kmsg_seq": entry.kmsg_seq,
+        ":monotonic_usec": entry.monotonic_usec,
+        ":pid": entry.pid,
+        ":uid": entry.uid,
+        ":gid": entry.gid,
+        ":comm": entry.comm,
+        ":exe": entry.exe,
+        ":cgroup": entry.cgroup,
+        ":container": entry.container,
+        ":stream": entry.stream,
+        ":truncated": entry.original_length.map(|_| true),
+        ":original_length": entry.original_length,
+        ":line_count": entry.line_count,
+        ":level": entry.level,
+        ":received_usec": entry.received_usec,
+        ":boottime_usec": entry.boottime_usec,
+        ":boot_id": boot::current().id,
+    })?;
+
+    if !entry.fields.is_empty() {
+        let log_id = conn.last_insert_rowid();
+        let mut stmt =
+            conn.prepare_cached("INSERT INTO log_fields (log_id, key, value) VALUES (?1, ?2, ?3)")?;
+        for (key, value) in &entry.fields {
+            stmt.execute(params![log_id, key, field_value(value)])?;
+        }
+    }
+    if let Some((name, count)) = entry.counter {
+        conn.prepare_cached(
+            "INSERT INTO counters (name, boot, value)
+             VALUES (?1, IFNULL((SELECT id FROM boots WHERE boot_id = ?2), 0), ?3)
+             ON CONFLICT (name, boot) DO UPDATE SET value = value + excluded.value",
+        )?
+        .execute(params![name, boot::current().id, count as i64])?;
+    }
+    Ok(())
+}
+
+/// Keep JSON scalars as native SQLite values so fields compare and sort
+/// naturally; nested objects and arrays are stored as JSON text.
+fn field_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
@@ -0,0 +1,126 @@
+use crate::clock;
+use crate::json;
+use crate::syslog;
+use serde_json::Value;
+
+/// Event time supplied by the producer
+#[derive(Debug, Clone)]
+pub enum EventTime {
+    /// Microseconds since the Unix epoch
+    Usec(i64),
+    /// Any date/time string SQLite's `datetime()` understands
+    Text(String),
+}
+
+/// A single log record as it is written to the `logs` table.
+///
+/// Only `source`, `severity` and `message` are always present; the
+/// remaining fields are filled in by inputs that carry the information.
+#[derive(Debug, Default, Clone)]
+pub struct Entry {
+    pub source: String,
+    pub severity: String,
+    pub message: String,
+    /// Numeric RFC 5424 level of `severity`, set by severity.rs
+    pub level: Option<u8>,
+    pub facility: Option<String>,
+    pub hostname: Option<String>,
+    pub app_name: Option<String>,
+    pub procid: Option<String>,
+    pub msgid: Option<String>,
+    pub structured_data: Option<String>,
+    /// Peer address for entries received over the network, path for
+    /// lines read from a followed file
+    pub origin: Option<String>,
+    /// Subject of the client certificate for entries received over TLS
+    pub peer_subject: Option<String>,
+    /// Sequence number of a kernel ring buffer record
+    pub kmsg_seq: Option<i64>,
+    /// `CLOCK_MONOTONIC` in microseconds when the entry was received; for
+    /// kernel records the time the kernel logged them
+    pub monotonic_usec: Option<i64>,
+    /// `CLOCK_BOOTTIME` in microseconds when the entry was received
+    pub boottime_usec: Option<i64>,
+    /// Wall-clock time in microseconds when the entry was received
+    pub received_usec: Option<i64>,
+    /// Sender process of local datagrams, from `SO_PASSCRED` and `/proc`
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub comm: Option<String>,
+    pub exe: Option<String>,
+    pub cgroup: Option<String>,
+    /// Container the sender runs in, derived from `cgroup`
+    pub container: Option<String>,
+    /// `stdout` or `stderr` for output of supervised programs
+    pub stream: Option<String>,
+    /// Size in bytes of a message that exceeded `max_message_size` and
+    /// was cut off; `None` for messages stored in full
+    pub original_length: Option<i64>,
+    /// Number of lines joined into this entry, see multiline.rs
+    pub line_count: Option<i64>,
+    /// Producer's timestamp; the receive time is used when absent
+    pub event_time: Option<EventTime>,
+    /// Extra key/value pairs of structured datagrams, see `log_fields`
+    pub fields: Vec<(String, Value)>,
+    /// Loss this entry of stylo's reports, added to `counters` along with
+    /// it (see status.rs); never set from what a producer sent
+    pub counter: Option<(&'static str, u64)>,
+}
+
+impl Entry {
+    pub fn new(source: &str, severity: &str, message: &str) -> Entry {
+        Entry {
+            source: source.to_string(),
+            severity: severity.to_string(),
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the time of receipt, unless an earlier step (or the kernel,
+    /// see cred.rs) already did.
+    pub fn stamp_received(&mut self) {
+        self.received_usec.get_or_insert_with(clock::realtime_usec);
+        self.monotonic_usec
+            .get_or_insert_with(clock::monotonic_usec);
+        self.boottime_usec.get_or_insert_with(clock::boottime_usec);
+    }
+
+    /// Parse a received message of `length` bytes of which `data` is the
+    /// part that fit into `max_message_size`.
+    pub fn parse_received(data: &[u8], length: usize) -> Entry {
+        let mut entry = Entry::parse(&String::from_utf8_lossy(data));
+        if length > data.len() {
+            entry.original_length = Some(length as i64);
+        }
+        entry
+    }
+
+    /// Parse a received datagram. Syslog frames (`<PRI>...`) are decoded
+    /// as RFC 5424 or RFC 3164 and JSON objects as structured entries,
+    /// everything else is read as stylo's own `SOURCE SEVERITY MESSAGE`
+    /// line and falls back to `unknown`/`RAW`.
+    pub fn parse(datagram: &str) -> Entry {
+        let msg = datagram.trim();
+
+        if msg.starts_with('{')
+            && let Some(entry) = json::parse(msg)
+        {
+            return entry;
+        }
+
+        if msg.starts_with('<')
+            && let Some(entry) = syslog::parse(msg)
+        {
+            return entry;
+        }
+
+        let parts: Vec<&str> = msg.splitn(3, ' ').collect();
+        if parts.len() == 3 {
+            Entry::new(parts[0], parts[1], parts[2])
+        } else {
+            Entry::new("unknown", "RAW", msg)
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Structured datagrams: a JSON object with `source`, `severity`,
+//! `message`, an optional `timestamp` and any number of extra fields.
+//!
+//! ```json
+//! {"source":"charon","severity":"ERROR","message":"upstream timeout",
+//!  "upstream":"9.9.9.9","timeout_ms":3000}
+//! ```
+//!
+//! Extra fields are stored in `log_fields`, one row per key.
+
+use crate::clock;
+use crate::entry::{Entry, EventTime};
+use serde_json::{Map, Value};
+
+/// Parse a JSON object. Returns `None` if the datagram is not an object
+/// or has no `message`, in which case it is stored as plain text.
+pub fn parse(datagram: &str) -> Option<Entry> {
+    let Value::Object(mut obj) = serde_json::from_str(datagram).ok()? else {
+        return None;
+    };
+
+    let message = take_string(&mut obj, "message")?;
+    let source = take_string(&mut obj, "source").unwrap_or_else(|| "unknown".to_string());
+    let severity = take_string(&mut obj, "severity").unwrap_or_else(|| "INFO".to_string());
+
+    let mut entry = Entry::new(&source, &severity, &message);
+    entry.event_time = match obj.remove("timestamp") {
+        // Seconds since the epoch, fractions allowed
+        Some(Value::Number(n)) => n
+            .as_f64()
+            .map(|secs| EventTime::Usec((secs * 1e6).round() as i64)),
+        Some(Value::String(s)) => Some(match clock::parse_rfc3339(&s) {
+            Some(usec) => EventTime::Usec(usec),
+            None => EventTime::Text(s),
+        }),
+        _ => None,
+    };
+    entry.fields = obj.into_iter().collect();
+    Some(entry)
+}
+
+fn take_string(obj: &mut Map<String, Value>, key: &str) -> Option<String> {
+    match obj.remove(key)? {
+        Value::String(s) => Some(s),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
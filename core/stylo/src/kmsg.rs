@@ -0,0 +1,171 @@
+//! Kernel ring buffer input from `/dev/kmsg`.
+//!
+//! Each read returns one record, `PRI,SEQ,USEC,FLAGS;MESSAGE`, optionally
+//! followed by ` KEY=VALUE` dictionary lines. The last imported sequence
+//! number is kept per boot in `kmsg_state`, written along with the records
+//! (see sink.rs), so a restarted daemon resumes where it stopped. Records
+//! the kernel overwrote before stylo could read them show up as gaps in
+//! the sequence and are logged as lost.
+
+use crate::boot;
+use crate::db;
+use crate::entry::Entry;
+use crate::severity;
+use crate::sink::{Position, Sink};
+use crate::syslog;
+use rusqlite::{Connection, OptionalExtension, Result, params};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::thread;
+use std::time::Duration;
+
+/// Larger than any record the kernel hands out in a single read.
+const RECORD_BUF: usize = 8192;
+
+pub fn get_kmsg_path() -> String {
+    if cfg!(debug_assertions) {
+        std::env::var("STYLO_KMSG").unwrap_or_else(|_| "/dev/kmsg".to_string())
+    } else {
+        "/dev/kmsg".to_string()
+    }
+}
+
+pub fn spawn(sink: Sink) -> Result<()> {
+    let path = get_kmsg_path();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not open kernel log {}: {}", path, e);
+            return Ok(());
+        }
+    };
+    println!("Stylo daemon reading {}", path);
+
+    let state = db::init_db()?;
+    let boot_id = boot::current().id.clone();
+    // Sequence numbers start at 0 on every boot
+    let last_seq = load_last_seq(&state, &boot_id)?.unwrap_or(-1);
+
+    thread::spawn(move || read_records(file, sink, boot_id, last_seq));
+    Ok(())
+}
+
+fn read_records(file: File, sink: Sink, boot_id: String, mut last_seq: i64) {
+    let mut reader = BufReader::with_capacity(RECORD_BUF, file);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            // Only regular files (used for testing) run dry
+            Ok(0) => {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+            Ok(_) => {}
+            // The ring buffer wrapped past our read position. The next
+            // read continues at the oldest record, the gap check below
+            // accounts for what was lost.
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(e) => {
+                eprintln!("Kernel log read error: {}", e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        }
+
+        // Dictionary lines belong to the record just read. They arrive in
+        // the same read, so only look at what is already buffered.
+        let mut dict = Vec::new();
+        while reader.buffer().first() == Some(&b' ') {
+            let mut cont = String::new();
+            if reader.read_line(&mut cont).is_err() {
+                break;
+            }
+            dict.push(cont.trim().to_string());
+        }
+
+        let Some(mut entry) = parse_record(&line) else {
+            continue;
+        };
+        let seq = entry.kmsg_seq.unwrap_or_default();
+        if seq <= last_seq {
+            continue;
+        }
+        if seq > last_seq + 1 {
+            let lost = seq - last_seq - 1;
+            sink.store(&Entry::new(
+                "kernel",
+                "WARNING",
+                &format!("{} kernel messages lost (ring buffer overrun)", lost),
+            ));
+        }
+
+        if !dict.is_empty() {
+            entry.structured_data = Some(format_dict(&dict));
+        }
+        sink.store(&entry);
+
+        last_seq = seq;
+        sink.save_position(Position::Kmsg {
+            boot_id: boot_id.clone(),
+            seq,
+        });
+    }
+}
+
+/// Parse a `PRI,SEQ,USEC,FLAGS;MESSAGE` record header.
+fn parse_record(line: &str) -> Option<Entry> {
+    let (header, message) = line.split_once(';')?;
+    let mut fields = header.split(',');
+    let pri: u32 = fields.next()?.parse().ok()?;
+    let seq: i64 = fields.next()?.parse().ok()?;
+    let usec: i64 = fields.next()?.parse().ok()?;
+
+    let mut entry = Entry::new(
+        "kernel",
+        severity::name((pri & 7) as u8),
+        message.trim_end(),
+    );
+    entry.facility = u8::try_from(pri >> 3)
+        .ok()
+        .and_then(syslog::facility_name)
+        .map(str::to_string);
+    entry.kmsg_seq = Some(seq);
+    entry.monotonic_usec = Some(usec);
+    Some(entry)
+}
+
+/// Render the dictionary as an RFC 5424 structured data element.
+fn format_dict(dict: &[String]) -> String {
+    let params: Vec<String> = dict
+        .iter()
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            format!(" {}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("[kmsg{}]", params.concat())
+}
+
+fn load_last_seq(conn: &Connection, boot_id: &str) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT last_seq FROM kmsg_state WHERE boot_id = ?1",
+        params![boot_id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub fn save_last_seq(conn: &Connection, boot_id: &str, seq: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO kmsg_state (boot_id, last_seq) VALUES (?1, ?2)
+         ON CONFLICT(boot_id) DO UPDATE SET last_seq = excluded.last_seq",
+        params![boot_id, seq],
+    )?;
+    Ok(())
+}
@@ -1,8 +1,52 @@
-use rusqlite::{params, Connection, Result};
+mod bench;
+mod boot;
+mod clock;
+mod config;
+mod container;
+mod cred;
+mod datagram;
+mod db;
+mod entry;
+mod json;
+mod kmsg;
+mod multiline;
+mod net;
+mod quota;
+mod retention;
+mod schema;
+mod search;
+mod severity;
+mod sink;
+mod status;
+mod syslog;
+mod tail;
+mod timesync;
+mod tls;
+mod wrap;
+
+use config::Config;
+use datagram::Datagrams;
+use entry::Entry;
+use multiline::Joiner;
+use rusqlite::Result;
+use sink::Sink;
 use std::env;
 use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixDatagram;
 use std::process;
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Datagrams taken from a socket per system call at most
+const RECV_BATCH: usize = 32;
+
+/// Receive loops holding entries back for joining. On shutdown each is
+/// sent an acknowledgement channel and answers once it has queued them.
+type Holders = Arc<Mutex<Vec<Sender<SyncSender<()>>>>>;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
@@ -11,6 +55,14 @@ fn main() -> Result<()> {
         match args[1].as_str() {
             "-d" | "--daemon" => return run_daemon(),
             "-c" | "--compact" => return run_cleanup(),
+            "boots" => return boot::run_list(),
+            "bench" => return bench::run(&args[2..]),
+            "migrate" => return schema::run_migrate(&args[2..]),
+            "retention" => return retention::run(&args[2..]),
+            "search" => return search::run(&args[2..]),
+            "status" => return status::run(),
+            "wrap" => return wrap::run(&args[2..]),
+            "container-log" => return wrap::run_container(&args[2..]),
             "-h" | "--help" => {
                 print_usage();
                 process::exit(0);
@@ -32,15 +84,23 @@ fn print_usage() {
     eprintln!("\nUsage:");
     eprintln!("  stylo [SOURCE] [SEVERITY] [MESSAGE]    Log a single message");
     eprintln!("  stylo -d / --daemon                    Start the logging daemon");
-    eprintln!("  stylo -c / --compact                   Clean logs > 24h and VACUUM database");
-}
-
-fn get_db_path() -> String {
-    if cfg!(debug_assertions) {
-        std::env::var("STYLO_DB").unwrap_or_else(|_| "log.db".to_string())
-    } else {
-        "/var/log.db".to_string()
-    }
+    eprintln!("  stylo -c / --compact                   Apply retention rules and VACUUM database");
+    eprintln!("  stylo boots                            List recorded boots");
+    eprintln!("  stylo bench [COUNT]                    Measure local socket reception");
+    eprintln!("  stylo bench --write [COUNT]            Measure database writes");
+    eprintln!("  stylo migrate [--dry-run]              Update the database schema");
+    eprintln!("  stylo retention                        List retention rules");
+    eprintln!("  stylo retention add [--source SOURCE] [--severity SEV[+|-]] AGE");
+    eprintln!(
+        "                                         Keep matching entries for AGE (30m, 12h, 90d)"
+    );
+    eprintln!("  stylo retention remove ID              Remove a retention rule");
+    eprintln!("  stylo search [-n LIMIT] TERMS...       Search messages, best matches first");
+    eprintln!("  stylo status                           Show database size, counts and losses");
+    eprintln!("  stylo wrap [-s SOURCE] [--stdout SEV] [--stderr SEV] -- COMMAND [ARGS...]");
+    eprintln!("                                         Run COMMAND and log its output");
+    eprintln!("  stylo container-log ID [--stdout SEV] [--stderr SEV] -- COMMAND [ARGS...]");
+    eprintln!("                                         Run a container and log its output");
 }
 
 fn get_socket_path() -> String {
@@ -51,92 +111,245 @@ fn get_socket_path() -> String {
     }
 }
 
-fn init_db() -> Result<Connection> {
-    let conn = Connection::open(get_db_path())?;
-    // Set busy timeout to handle concurrent writes from oneshot calls
-    conn.pragma_update(None, "busy_timeout", "5000")?;
-    conn.pragma_update(None, "journal_mode", "WAL")?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS logs (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-            source TEXT NOT NULL,
-            severity TEXT NOT NULL,
-            message TEXT NOT NULL
-        )",
-        [],
-    )?;
-    Ok(conn)
+fn get_syslog_path() -> String {
+    if cfg!(debug_assertions) {
+        std::env::var("STYLO_SYSLOG_SOCK").unwrap_or_else(|_| "syslog.sock".to_string())
+    } else {
+        "/dev/log".to_string()
+    }
 }
 
 fn run_oneshot(source: &str, severity: &str, message: &str) -> Result<()> {
-    let conn = init_db()?;
-    conn.execute(
-        "INSERT INTO logs (source, severity, message) VALUES (?1, ?2, ?3)",
-        params![source, severity, message],
-    )?;
-    Ok(())
+    let mut entry = Entry::new(source, severity, message);
+    entry.stamp_received();
+    if let Err(reason) = severity::normalize(&mut entry, Config::load().unknown_severity) {
+        eprintln!("{}", reason);
+        process::exit(1);
+    }
+    let conn = db::init_db()?;
+    db::insert_entry(&conn, &entry)
 }
 
 fn run_cleanup() -> Result<()> {
-    let db_path = get_db_path();
+    let db_path = db::get_db_path();
     println!("Starting database maintenance: {}", db_path);
 
-    let conn = init_db()?;
+    let conn = db::init_db()?;
 
-    // 1. Delete logs older than 24 hours
-    let deleted = conn.execute(
-        "DELETE FROM logs WHERE timestamp < datetime('now', '-24 hours')",
-        [],
-    )?;
-    println!("Deleted {} old log entries.", deleted);
+    // 1. Delete logs past their retention time
+    for (rule, deleted) in retention::apply(&conn)? {
+        println!(
+            "Deleted {} entries under rule {} ({}, {}).",
+            deleted,
+            rule.id,
+            rule.describe(),
+            retention::format_age(rule.keep_seconds)
+        );
+    }
+
+    // 2. Get back under the size limit, which the daemon only keeps while
+    // it is running
+    if let Some(limit) = quota::limit(&Config::load()) {
+        let mut deleted = [0; 8];
+        loop {
+            let round = quota::enforce(&conn, limit)?;
+            if round == [0; 8] {
+                break;
+            }
+            for (n, more) in deleted.iter_mut().zip(round) {
+                *n += more;
+            }
+        }
+        let total: u64 = deleted.iter().sum();
+        if total > 0 {
+            println!(
+                "Deleted {} entries over the size limit ({}).",
+                total,
+                severity::summary(&deleted)
+            );
+        }
+    }
 
-    // 2. Reclaim disk space
+    // 3. Reclaim disk space, including the full-text index's
+    search::optimize(&conn)?;
     println!("Running VACUUM...");
     conn.execute("VACUUM", [])?;
 
+    // 4. Refresh the statistics the query planner picks indexes by; 0x10002
+    // looks at every table, not only those this connection queried
+    conn.execute_batch("PRAGMA optimize = 0x10002")?;
+
     println!("Maintenance complete.");
     Ok(())
 }
 
 fn run_daemon() -> Result<()> {
-    let conn = init_db()?;
-    let socket_path = get_socket_path(); // Dies ist nun ein String
-
-    // Wir übergeben eine Referenz (&), damit wir die Ownership behalten
+    // Before any thread starts, so that all of them inherit the mask
+    let signals = block_signals(&[libc::SIGTERM, libc::SIGINT]);
+    let cfg = Config::load();
+    search::configure(&db::init_db()?, cfg.full_text_search())?;
+    let forward = cfg
+        .tls_forward
+        .as_deref()
+        .map(|target| tls::spawn_forwarder(target, &cfg));
+    let sink = Sink::open(&cfg, forward)?;
+    let shutdown_sink = sink.clone();
+    let holders = Holders::default();
+    let shutdown_holders = holders.clone();
+    thread::spawn(move || wait_for_shutdown(signals, shutdown_sink, shutdown_holders));
+    let socket_path = get_socket_path();
     let _ = fs::remove_file(&socket_path);
-
-    // Auch hier binden wir per Referenz
     let socket = UnixDatagram::bind(&socket_path)
-        .unwrap_or_else(|e| {
-            // Da wir oben nur geliehen haben, ist socket_path hier noch verfügbar
-            panic!("Could not bind socket {}: {}", socket_path, e)
-        });
-
+        .unwrap_or_else(|e| panic!("Could not bind socket {}: {}", socket_path, e));
     println!("Stylo daemon listening on {}", socket_path);
 
-    let mut buf = [0u8; 4096];
+    // /dev/log is optional: without it stylo still serves its own socket,
+    // e.g. when running unprivileged during development.
+    let syslog_path = get_syslog_path();
+    let _ = fs::remove_file(&syslog_path);
+    match UnixDatagram::bind(&syslog_path) {
+        Ok(syslog_socket) => {
+            // Every process must be able to call syslog(3)
+            let _ = fs::set_permissions(&syslog_path, fs::Permissions::from_mode(0o666));
+            println!("Stylo daemon listening on {}", syslog_path);
+            let syslog_sink = sink.clone();
+            let syslog_cfg = cfg.clone();
+            let syslog_holders = holders.clone();
+            thread::spawn(move || {
+                serve_datagrams(syslog_socket, syslog_sink, &syslog_cfg, &syslog_holders)
+            });
+        }
+        Err(e) => eprintln!("Could not bind syslog socket {}: {}", syslog_path, e),
+    }
+
+    kmsg::spawn(sink.clone())?;
+    tail::spawn(&cfg, sink.clone())?;
+    timesync::spawn(sink.clone())?;
+
+    if let Some(addr) = cfg.udp_listen {
+        net::spawn_udp(addr, &cfg, sink.clone());
+    }
+    if let Some(addr) = cfg.tcp_listen {
+        net::spawn_tcp(addr, cfg.max_message_size(), sink.clone());
+    }
+    if let Some(addr) = cfg.tls_listen {
+        tls::spawn_listener(addr, &cfg, sink.clone());
+    }
+
+    serve_datagrams(socket, sink, &cfg, &holders);
+    Ok(())
+}
+
+/// Block `signals` so they are only taken by `sigwait`, e.g. in
+/// `wait_for_shutdown`. Child processes inherit the mask.
+pub fn block_signals(list: &[libc::c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        for signal in list {
+            libc::sigaddset(&mut signals, *signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+/// Commit the pending batch, including the entries the receive loops hold
+/// back for joining, before exiting on a shutdown signal.
+fn wait_for_shutdown(signals: libc::sigset_t, sink: Sink, holders: Holders) {
+    let mut signal = 0;
+    unsafe { libc::sigwait(&signals, &mut signal) };
+    let pending: Vec<_> = holders
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|holder| {
+            let (ack, done) = mpsc::sync_channel(1);
+            holder.send(ack).ok().map(|_| done)
+        })
+        .collect();
+    // The loops wake up at least once a second
+    for done in pending {
+        let _ = done.recv();
+    }
+    sink.flush();
+    process::exit(0);
+}
+
+/// Receive datagrams on `socket` and store them until the process exits.
+/// Each entry is attributed to its sending process, see cred.rs.
+fn serve_datagrams(socket: UnixDatagram, sink: Sink, cfg: &Config, holders: &Holders) {
+    if let Err(e) = datagram::enable_passcred(&socket) {
+        eprintln!("Could not enable SO_PASSCRED: {}", e);
+    }
+    if let Err(e) = datagram::enable_timestamps(&socket) {
+        eprintln!("Could not enable SO_TIMESTAMP: {}", e);
+    }
+    if let Err(e) = datagram::enable_drop_count(&socket) {
+        eprintln!("Could not enable SO_RXQ_OVFL: {}", e);
+    }
+    if let Some(size) = cfg.receive_buffer
+        && let Err(e) = datagram::set_receive_buffer(&socket, size)
+    {
+        eprintln!("Could not set receive buffer size: {}", e);
+    }
+    let name = socket
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+        .unwrap_or_default();
+
+    let mut joiner = Joiner::new(cfg);
+    let (holder, shutdown) = mpsc::channel::<SyncSender<()>>();
+    if joiner.is_active() {
+        // Wake up regularly to store entries that were not continued and
+        // to notice a shutdown
+        let wake = joiner.timeout().min(Duration::from_secs(1));
+        let _ = socket.set_read_timeout(Some(wake));
+        holders.lock().unwrap().push(holder);
+    }
+
+    let mut datagrams = Datagrams::new(RECV_BATCH, cfg.max_message_size());
+    let mut lookups = cred::Lookups::default();
     loop {
-        match socket.recv_from(&mut buf) {
-            Ok((size, _)) => {
-                let msg_str = String::from_utf8_lossy(&buf[..size]);
-                let msg_trimmed = msg_str.trim();
-
-                let parts: Vec<&str> = msg_trimmed.splitn(3, ' ').collect();
-                if parts.len() == 3 {
-                    let _ = conn.execute(
-                        "INSERT INTO logs (source, severity, message) VALUES (?1, ?2, ?3)",
-                        params![parts[0], parts[1], parts[2]],
-                    );
-                } else {
-                    let _ = conn.execute(
-                        "INSERT INTO logs (source, severity, message) VALUES (?1, ?2, ?3)",
-                        params!["unknown", "RAW", msg_trimmed],
-                    );
+        let mut entries = Vec::new();
+        match datagrams.recv(&socket) {
+            Ok(_) => {
+                lookups.clear();
+                if datagrams.lost() > 0 {
+                    entries.push(datagram::lost_entry(&name, datagrams.lost()));
+                }
+                for datagram in datagrams.iter() {
+                    let mut entry = Entry::parse_received(datagram.data, datagram.size);
+                    entry.received_usec = datagram.received_usec;
+                    if let Err(reason) =
+                        cred::attribute(&mut entry, datagram.creds, cfg, &mut lookups)
+                    {
+                        entries.push(cred::rejection(&entry, &reason));
+                        continue;
+                    }
+                    if datagram.control_truncated {
+                        // Padded with descriptors, e.g. to push the
+                        // credentials out; never trust such a datagram
+                        entries.push(cred::rejection(
+                            &entry,
+                            "Rejected datagram with truncated control data",
+                        ));
+                        continue;
+                    }
+                    entries.extend(joiner.push(entry));
                 }
             }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
             Err(e) => eprintln!("Socket read error: {}", e),
         }
+        entries.extend(joiner.expired());
+        if let Ok(ack) = shutdown.try_recv() {
+            entries.extend(joiner.flush());
+            sink.store_batch(entries);
+            let _ = ack.send(());
+            continue;
+        }
+        sink.store_batch(entries);
     }
 }
@@ -0,0 +1,132 @@
+//! Multi-line reassembly.
+//!
+//! Producers that write one datagram or output line per line of text split
+//! a backtrace into dozens of entries. For sources with a
+//! `multiline.<source>` rule, lines that continue the previous entry are
+//! appended to it. The entry is stored once a line starts a new one, the
+//! joined message would exceed `max_message_size`, or no line arrived for
+//! `multiline_timeout_ms`. It keeps the first line's time and records the
+//! number of lines in `line_count`.
+
+use crate::config::{Config, Continuation};
+use crate::entry::Entry;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Lines are only joined with earlier lines of the same producer: same
+/// source, process, output stream and peer.
+type Key = (String, Option<i32>, Option<String>, Option<String>);
+
+struct Pending {
+    entry: Entry,
+    deadline: Instant,
+}
+
+pub struct Joiner {
+    rules: HashMap<String, Continuation>,
+    timeout: Duration,
+    max_size: usize,
+    pending: HashMap<Key, Pending>,
+}
+
+impl Joiner {
+    pub fn new(cfg: &Config) -> Joiner {
+        Joiner {
+            rules: cfg.multiline.clone(),
+            timeout: cfg.multiline_timeout(),
+            max_size: cfg.max_message_size(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Without rules every entry passes straight through and no input
+    /// needs to wake up for timeouts.
+    pub fn is_active(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Feed one line and return the entries it completed.
+    pub fn push(&mut self, mut entry: Entry) -> Vec<Entry> {
+        let Some(rule) = self.rules.get(&entry.source) else {
+            return vec![entry];
+        };
+        let continues = match rule {
+            Continuation::Indent => entry.message.starts_with([' ', '\t']),
+            Continuation::Start(pattern) => !pattern.is_match(&entry.message),
+        };
+
+        let key = key(&entry);
+        let mut done = Vec::new();
+        if let Some(mut pending) = self.pending.remove(&key) {
+            if continues && pending.entry.message.len() + 1 + entry.message.len() <= self.max_size {
+                pending.entry.message.push('\n');
+                pending.entry.message.push_str(&entry.message);
+                pending.entry.line_count = pending.entry.line_count.map(|n| n + 1);
+                pending.deadline = Instant::now() + self.timeout;
+                self.pending.insert(key, pending);
+                return done;
+            }
+            done.push(pending.entry);
+        }
+
+        // Stored later, so pin the time the first line arrived
+        entry.stamp_received();
+        entry.line_count = Some(1);
+        self.pending.insert(
+            key,
+            Pending {
+                entry,
+                deadline: Instant::now() + self.timeout,
+            },
+        );
+        done
+    }
+
+    /// Entries that have not been continued within the timeout.
+    pub fn expired(&mut self) -> Vec<Entry> {
+        let now = Instant::now();
+        let keys: Vec<Key> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.iter()
+            .filter_map(|key| self.pending.remove(key))
+            .map(|pending| pending.entry)
+            .collect()
+    }
+
+    /// The incomplete entry that lines from the producer of `like` would
+    /// be appended to.
+    pub fn pending(&self, like: &Entry) -> Option<&Entry> {
+        self.pending.get(&key(like)).map(|pending| &pending.entry)
+    }
+
+    /// Take the incomplete entry of the producer of `like`, for an input
+    /// that ends, e.g. a rotated file.
+    pub fn take(&mut self, like: &Entry) -> Option<Entry> {
+        self.pending.remove(&key(like)).map(|pending| pending.entry)
+    }
+
+    /// All incomplete entries, for inputs that are shutting down.
+    pub fn flush(&mut self) -> Vec<Entry> {
+        self.pending
+            .drain()
+            .map(|(_, pending)| pending.entry)
+            .collect()
+    }
+}
+
+fn key(entry: &Entry) -> Key {
+    (
+        entry.source.clone(),
+        entry.pid,
+        entry.stream.clone(),
+        entry.origin.clone(),
+    )
+}
@@ -0,0 +1,286 @@
+//! Network syslog listeners: UDP (RFC 5426) and TCP (RFC 6587).
+//!
+//! Every frame is parsed like a local datagram and stored with the peer
+//! address in the `origin` column. Frames longer than `max_message_size`
+//! are stored truncated, see `Entry::original_length`; no more than that
+//! is ever held in memory.
+//!
+//! Stream listeners serve at most `MAX_CONNECTIONS` connections at once,
+//! `MAX_PEER_CONNECTIONS` of them from the same address, and close those
+//! that stay silent for `IDLE_TIMEOUT`, so that neither threads nor
+//! descriptors pile up behind clients that never hang up, and a single
+//! client cannot take all connections.
+
+use crate::config::Config;
+use crate::datagram::{self, Datagrams};
+use crate::entry::Entry;
+use crate::sink::Sink;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Connections a stream listener serves at once; more are refused
+pub const MAX_CONNECTIONS: usize = 256;
+/// Connections a stream listener serves at once from a single address
+pub const MAX_PEER_CONNECTIONS: usize = 16;
+/// How long a connection may stay silent before it is closed
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Longest octet count accepted, in digits
+const MAX_COUNT_DIGITS: u64 = 10;
+
+pub fn spawn_udp(addr: SocketAddr, cfg: &Config, sink: Sink) {
+    let socket = UdpSocket::bind(addr)
+        .unwrap_or_else(|e| panic!("Could not bind UDP listener {}: {}", addr, e));
+    if let Err(e) = datagram::enable_drop_count(&socket) {
+        eprintln!("Could not enable SO_RXQ_OVFL: {}", e);
+    }
+    if let Some(size) = cfg.receive_buffer
+        && let Err(e) = datagram::set_receive_buffer(&socket, size)
+    {
+        eprintln!("Could not set receive buffer size: {}", e);
+    }
+    println!("Stylo daemon listening on udp://{}", addr);
+
+    let max_size = cfg.max_message_size();
+    thread::spawn(move || {
+        // Large enough for any UDP payload, so nothing is cut off
+        let mut datagrams = Datagrams::new(crate::RECV_BATCH, 65535);
+        let name = format!("udp://{}", addr);
+        loop {
+            if let Err(e) = datagrams.recv(&socket) {
+                eprintln!("UDP read error: {}", e);
+                continue;
+            }
+            if datagrams.lost() > 0 {
+                sink.store(&datagram::lost_entry(&name, datagrams.lost()));
+            }
+            for datagram in datagrams.iter() {
+                let Some(peer) = datagram.peer else {
+                    continue;
+                };
+                let kept = datagram.size.min(max_size);
+                store(&sink, &datagram.data[..kept], datagram.size, peer, None);
+            }
+        }
+    });
+}
+
+pub fn spawn_tcp(addr: SocketAddr, max_size: usize, sink: Sink) {
+    let listener = TcpListener::bind(addr)
+        .unwrap_or_else(|e| panic!("Could not bind TCP listener {}: {}", addr, e));
+    println!("Stylo daemon listening on tcp://{}", addr);
+
+    thread::spawn(move || {
+        let connections = Connections::default();
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TCP accept error: {}", e);
+                    continue;
+                }
+            };
+            let Ok(peer) = stream.peer_addr() else {
+                continue;
+            };
+            let Some(admitted) = connections.admit(peer) else {
+                continue;
+            };
+            if let Err(e) = stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+                eprintln!("Could not set timeout for {}: {}", peer, e);
+                continue;
+            }
+            let conn_sink = sink.clone();
+            thread::spawn(move || {
+                serve_frames(stream, peer, None, max_size, &conn_sink);
+                drop(admitted);
+            });
+        }
+    });
+}
+
+/// Open connections of a stream listener, overall and per address.
+#[derive(Default)]
+struct Open {
+    total: usize,
+    per_peer: HashMap<IpAddr, usize>,
+}
+
+/// Count of the open connections of a stream listener.
+#[derive(Default)]
+pub struct Connections(Arc<Mutex<Open>>);
+
+/// An admitted connection, counted until it is dropped.
+pub struct Admitted {
+    open: Arc<Mutex<Open>>,
+    ip: IpAddr,
+}
+
+impl Connections {
+    /// Count a connection from `peer`, or refuse it if `MAX_CONNECTIONS`
+    /// are open already, or `MAX_PEER_CONNECTIONS` from its address.
+    pub fn admit(&self, peer: SocketAddr) -> Option<Admitted> {
+        let mut open = self.0.lock().unwrap();
+        if open.total >= MAX_CONNECTIONS {
+            eprintln!(
+                "Refused connection from {}, {} open already",
+                peer, MAX_CONNECTIONS
+            );
+            return None;
+        }
+        let from_peer = open.per_peer.entry(peer.ip()).or_default();
+        if *from_peer >= MAX_PEER_CONNECTIONS {
+            eprintln!(
+                "Refused connection from {}, {} open from that address already",
+                peer, MAX_PEER_CONNECTIONS
+            );
+            return None;
+        }
+        *from_peer += 1;
+        open.total += 1;
+        Some(Admitted {
+            open: self.0.clone(),
+            ip: peer.ip(),
+        })
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(from_peer) = open.per_peer.get_mut(&self.ip) {
+            *from_peer -= 1;
+            if *from_peer == 0 {
+                open.per_peer.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Store frames from a stream connection until the peer hangs up. Shared
+/// by the TCP and TLS listeners.
+pub fn serve_frames<R: Read>(
+    stream: R,
+    peer: SocketAddr,
+    peer_subject: Option<&str>,
+    max_size: usize,
+    sink: &Sink,
+) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        match read_frame(&mut reader, max_size) {
+            Ok(Some((frame, length))) => store(sink, &frame, length, peer, peer_subject),
+            Ok(None) => return,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                eprintln!("Closing idle connection from {}", peer);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Read error from {}: {}", peer, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Read one RFC 6587 frame. A frame starting with a digit uses octet
+/// counting (`LEN SP MSG`), anything else is newline-terminated.
+/// Returns at most `max_size` bytes of the frame along with its full
+/// length, or `None` once the peer has closed the connection.
+fn read_frame<R: BufRead>(reader: &mut R, max_size: usize) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(b) => *b,
+        None => return Ok(None),
+    };
+
+    let mut frame = Vec::new();
+    if first.is_ascii_digit() {
+        reader
+            .take(MAX_COUNT_DIGITS + 1)
+            .read_until(b' ', &mut frame)?;
+        let len = std::str::from_utf8(&frame)
+            .ok()
+            .and_then(|s| s.strip_suffix(' '))
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad octet count"))?;
+        frame.clear();
+        frame.resize(len.min(max_size), 0);
+        reader.read_exact(&mut frame)?;
+        // Skip the rest of an oversized frame to stay in sync
+        let rest = (len - frame.len()) as u64;
+        if io::copy(&mut reader.take(rest), &mut io::sink())? < rest {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some((frame, len)))
+    } else {
+        let len = read_line(reader, max_size, &mut frame)?.unwrap_or_default();
+        Ok(Some((frame, len)))
+    }
+}
+
+/// Read a newline-terminated line into `line`, without the newline and
+/// cut off after `max_size` bytes; the rest is skipped without being held
+/// in memory. Returns the full length of the line, or `None` at the end of
+/// the stream. Also used for the output of wrapped programs.
+pub fn read_line<R: BufRead>(
+    reader: &mut R,
+    max_size: usize,
+    line: &mut Vec<u8>,
+) -> io::Result<Option<usize>> {
+    line.clear();
+    reader.take(max_size as u64 + 1).read_until(b'\n', line)?;
+    let mut len = line.len();
+    if len == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        len -= 1;
+    } else if len > max_size {
+        len += skip_line(reader)?;
+        line.truncate(max_size);
+    }
+    Ok(Some(len))
+}
+
+/// Skip the rest of an oversized line, up to and including the newline.
+/// Returns the number of bytes skipped before it.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<usize> {
+    let mut skipped = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(skipped);
+        }
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(skipped + end);
+            }
+            None => {
+                let size = buf.len();
+                reader.consume(size);
+                skipped += size;
+            }
+        }
+    }
+}
+
+fn store(sink: &Sink, frame: &[u8], length: usize, peer: SocketAddr, peer_subject: Option<&str>) {
+    if frame.iter().all(u8::is_ascii_whitespace) {
+        return;
+    }
+    let mut entry = Entry::parse_received(frame, length);
+    entry.origin = Some(peer.to_string());
+    entry.peer_subject = peer_subject.map(str::to_string);
+    sink.store(&entry);
+}
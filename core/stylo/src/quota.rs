@@ -0,0 +1,152 @@
+//! Size limit of the database.
+//!
+//! `/var` is a small disk image, and a single noisy source can fill it
+//! long before `--compact` deletes anything by age (see retention.rs).
+//! The daemon's writer therefore checks the database after every commit;
+//! once it takes more than `max_db_size` (bytes, or a percentage of its
+//! filesystem), entries are deleted least severe first and, within a
+//! severity, oldest first, until it is back under 90% of the limit.
+//! Deletions are recorded as a `WARNING` from `stylo` with the total in
+//! the `deleted` field.
+//!
+//! Deleted rows leave free pages that later inserts reuse, so the file
+//! stops growing; only `VACUUM` (`stylo --compact`) makes it smaller.
+
+use crate::config::{Config, SizeLimit};
+use crate::db;
+use crate::entry::Entry;
+use crate::severity;
+use rusqlite::{Connection, Result, Transaction, TransactionBehavior};
+use serde_json::Value;
+use std::ffi::CString;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Rows deleted per transaction, keeping the write lock short
+const CHUNK: usize = 1000;
+
+/// Rows deleted per check at most, so that one check never stalls the
+/// writer for long; the next one carries on
+const ROUND: u64 = 50_000;
+
+/// Levels in the order they are deleted in. Entries with an unknown
+/// severity rank with INFO, as in the write queue.
+const VICTIMS: [Option<u8>; 9] = [
+    Some(7),
+    Some(6),
+    None,
+    Some(5),
+    Some(4),
+    Some(3),
+    Some(2),
+    Some(1),
+    Some(0),
+];
+
+/// The limit in bytes, if there is one.
+pub fn limit(cfg: &Config) -> Option<u64> {
+    match cfg.max_db_size() {
+        SizeLimit::Off => None,
+        SizeLimit::Bytes(bytes) => Some(bytes),
+        SizeLimit::Percent(percent) => {
+            filesystem_size(&db::get_db_path()).map(|size| size / 100 * percent)
+        }
+    }
+}
+
+fn filesystem_size(db_path: &str) -> Option<u64> {
+    let dir = Path::new(db_path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let c_dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_dir.as_ptr(), &mut stat) } < 0 {
+        eprintln!(
+            "Could not get the size of {}: {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(stat.f_blocks as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes of the database in use, not counting free pages.
+pub fn used(conn: &Connection) -> Result<u64> {
+    conn.query_row(
+        "SELECT (page_count - freelist_count) * page_size
+         FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+        [],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|bytes| bytes as u64)
+}
+
+/// Delete entries until the database is back under 90% of `limit`, if it
+/// exceeds `limit`. Returns the number deleted per level.
+pub fn enforce(conn: &Connection, limit: u64) -> Result<[u64; 8]> {
+    let mut deleted = [0; 8];
+    if used(conn)? <= limit {
+        return Ok(deleted);
+    }
+    let target = limit / 10 * 9;
+    let mut total = 0;
+    for level in VICTIMS {
+        loop {
+            if total >= ROUND || used(conn)? <= target {
+                return Ok(deleted);
+            }
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let n = tx
+                .prepare_cached(
+                    "DELETE FROM logs WHERE id IN (
+                        SELECT id FROM logs WHERE level IS ?1 ORDER BY id LIMIT ?2)",
+                )?
+                .execute((level, CHUNK as i64))?;
+            tx.commit()?;
+            deleted[level.unwrap_or(severity::DEFAULT_LEVEL) as usize] += n as u64;
+            total += n as u64;
+            if n < CHUNK {
+                break;
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/// Enforce the limit from the daemon's writer and record what it took.
+pub fn check(conn: &Connection, limit: u64) {
+    let result = enforce(conn, limit).and_then(|deleted| match report(&deleted, limit) {
+        Some(report) => db::insert_entry(conn, &report),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        eprintln!("Could not enforce the database size limit: {}", e);
+    }
+}
+
+fn report(deleted: &[u64; 8], limit: u64) -> Option<Entry> {
+    let total: u64 = deleted.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut entry = Entry::new(
+        "stylo",
+        "WARNING",
+        &format!(
+            "Deleted {} entries, database over its size limit of {:.1} MiB ({})",
+            total,
+            limit as f64 / (1 << 20) as f64,
+            severity::summary(deleted)
+        ),
+    );
+    entry.level = Some(4);
+    entry
+        .fields
+        .push(("deleted".to_string(), Value::from(total)));
+    entry.counter = Some(("deleted", total));
+    entry.stamp_received();
+    Some(entry)
+}
@@ -0,0 +1,258 @@
+//! Retention rules, applied by `stylo -c`.
+//!
+//! Each rule in `retention_rules` keeps the entries it matches for
+//! `keep_seconds`. A rule matches by `source`, by a range of levels
+//! (`level_min` is the most severe level it covers, `level_max` the least
+//! severe) or both; a rule without either is the default. Every entry falls
+//! under the most specific rule that matches it: rules for a source before
+//! the others, narrower severity ranges before wider ones. So with
+//!
+//! ```text
+//! source=charon DEBUG   1h
+//! ERROR and above       90d
+//! default               7d
+//! ```
+//!
+//! charon's DEBUG lines go after an hour, its errors after 90 days.
+//!
+//! Age is counted from `received_usec`, stylo's own clock, not from the
+//! `timestamp` a producer may have supplied: a clock that is off on the
+//! sender must not keep entries forever or delete them early. Entries
+//! without a receive time, written by older versions, go by `timestamp`.
+//! Each rule is one `DELETE` that excludes the entries of the rules before
+//! it; the planner picks between the `received_usec`, `(source, id)` and
+//! `(level, id)` indexes, so none of them scans the whole table.
+
+use crate::clock;
+use crate::db;
+use crate::severity;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, Result, params, params_from_iter};
+use std::process;
+
+pub struct Rule {
+    pub id: i64,
+    source: Option<String>,
+    level_min: Option<u8>,
+    level_max: Option<u8>,
+    pub keep_seconds: i64,
+}
+
+impl Rule {
+    /// SQL condition for the entries the rule covers, appending its
+    /// parameters to `params`. Never NULL, so that it can be negated.
+    fn condition(&self, params: &mut Vec<SqlValue>) -> String {
+        let mut terms = Vec::new();
+        if let Some(source) = &self.source {
+            terms.push("source = ?");
+            params.push(SqlValue::Text(source.clone()));
+        }
+        if self.level_min.is_some() || self.level_max.is_some() {
+            terms.push("level IS NOT NULL");
+        }
+        match (self.level_min, self.level_max) {
+            (Some(min), Some(max)) if min == max => {
+                terms.push("level = ?");
+                params.push(SqlValue::Integer(min as i64));
+            }
+            (min, max) => {
+                if let Some(min) = min {
+                    terms.push("level >= ?");
+                    params.push(SqlValue::Integer(min as i64));
+                }
+                if let Some(max) = max {
+                    terms.push("level <= ?");
+                    params.push(SqlValue::Integer(max as i64));
+                }
+            }
+        }
+        if terms.is_empty() {
+            return "1".to_string();
+        }
+        format!("({})", terms.join(" AND "))
+    }
+
+    /// Which entries the rule covers, like `source=charon ERROR and above`.
+    pub fn describe(&self) -> String {
+        let levels = match (self.level_min, self.level_max) {
+            (None, None) => None,
+            (Some(min), Some(max)) if min == max => Some(severity::name(min).to_string()),
+            (Some(min), Some(max)) => Some(format!(
+                "{} to {}",
+                severity::name(min),
+                severity::name(max)
+            )),
+            (Some(min), None) => Some(format!("{} and below", severity::name(min))),
+            (None, Some(max)) => Some(format!("{} and above", severity::name(max))),
+        };
+        let source = self
+            .source
+            .as_ref()
+            .map(|source| format!("source={}", source));
+        match (source, levels) {
+            (None, None) => "default".to_string(),
+            (Some(source), Some(levels)) => format!("{} {}", source, levels),
+            (source, levels) => source.or(levels).unwrap_or_default(),
+        }
+    }
+}
+
+/// The rules, most specific first.
+pub fn load(conn: &Connection) -> Result<Vec<Rule>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, source, level_min, level_max, keep_seconds FROM retention_rules
+         ORDER BY source IS NULL, COALESCE(level_max, 7) - COALESCE(level_min, 0), id",
+    )?;
+    stmt.query_map([], |row| {
+        Ok(Rule {
+            id: row.get(0)?,
+            source: row.get(1)?,
+            level_min: row.get(2)?,
+            level_max: row.get(3)?,
+            keep_seconds: row.get(4)?,
+        })
+    })?
+    .collect()
+}
+
+/// Delete the entries past their rule's retention time. Returns the rules
+/// with the number of entries each deleted.
+pub fn apply(conn: &Connection) -> Result<Vec<(Rule, usize)>> {
+    let rules = load(conn)?;
+    let mut deleted = Vec::with_capacity(rules.len());
+    for (i, rule) in rules.iter().enumerate() {
+        let cutoff = clock::realtime_usec() - rule.keep_seconds.saturating_mul(1_000_000);
+        let mut params = vec![
+            SqlValue::Integer(cutoff),
+            SqlValue::Text(format!("-{} seconds", rule.keep_seconds)),
+        ];
+        let mut sql = "DELETE FROM logs
+             WHERE (received_usec < ?1
+                    OR received_usec IS NULL AND timestamp < datetime('now', ?2))
+             AND "
+            .to_string();
+        sql.push_str(&rule.condition(&mut params));
+        for earlier in &rules[..i] {
+            sql.push_str(" AND NOT ");
+            sql.push_str(&earlier.condition(&mut params));
+        }
+        let n = conn.execute(&sql, params_from_iter(params))?;
+        deleted.push(n);
+    }
+    Ok(rules.into_iter().zip(deleted).collect())
+}
+
+/// `stylo retention [add [--source SOURCE] [--severity SEV[+|-]] AGE | remove ID]`
+pub fn run(args: &[String]) -> Result<()> {
+    let conn = db::init_db()?;
+    match args.split_first() {
+        None => list(&conn),
+        Some((command, rest)) if command == "add" => add(&conn, rest),
+        Some((command, [id])) if command == "remove" => {
+            let removed = match id.parse::<i64>() {
+                Ok(id) => conn.execute("DELETE FROM retention_rules WHERE id = ?1", [id])?,
+                Err(_) => 0,
+            };
+            if removed == 0 {
+                eprintln!("No retention rule {}", id);
+                process::exit(1);
+            }
+            Ok(())
+        }
+        _ => {
+            crate::print_usage();
+            process::exit(1);
+        }
+    }
+}
+
+fn list(conn: &Connection) -> Result<()> {
+    for rule in load(conn)? {
+        println!(
+            "{:>3}  {:<32} {}",
+            rule.id,
+            rule.describe(),
+            format_age(rule.keep_seconds)
+        );
+    }
+    Ok(())
+}
+
+/// Add a rule, or change the age of the one for the same entries.
+fn add(conn: &Connection, args: &[String]) -> Result<()> {
+    let mut source = None;
+    let (mut level_min, mut level_max) = (None, None);
+    let mut age = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--source" => source = iter.next().cloned(),
+            "--severity" => {
+                let spec = iter.next().map(String::as_str).unwrap_or_default();
+                let (name, range) = match spec.strip_suffix(['+', '-']) {
+                    Some(name) => (name, spec.chars().last()),
+                    None => (spec, None),
+                };
+                let Some(level) = severity::level(name) else {
+                    eprintln!("Invalid severity: {}", spec);
+                    process::exit(1);
+                };
+                (level_min, level_max) = match range {
+                    Some('+') => (None, Some(level)),
+                    Some(_) => (Some(level), None),
+                    None => (Some(level), Some(level)),
+                };
+            }
+            _ if age.is_none() => {
+                age = Some(parse_age(arg).unwrap_or_else(|| {
+                    eprintln!("Invalid age: {}", arg);
+                    process::exit(1);
+                }))
+            }
+            _ => {
+                crate::print_usage();
+                process::exit(1);
+            }
+        }
+    }
+    let Some(keep_seconds) = age else {
+        crate::print_usage();
+        process::exit(1);
+    };
+
+    // The rule keeps its id, so `remove` and the reports of `-c` go on
+    // referring to it
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let updated = conn.execute(
+        "UPDATE retention_rules SET keep_seconds = ?4
+         WHERE source IS ?1 AND level_min IS ?2 AND level_max IS ?3",
+        params![source, level_min, level_max, keep_seconds],
+    )?;
+    if updated == 0 {
+        conn.execute(
+            "INSERT INTO retention_rules (source, level_min, level_max, keep_seconds)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![source, level_min, level_max, keep_seconds],
+        )?;
+    }
+    conn.execute_batch("COMMIT")
+}
+
+const UNITS: [(char, i64); 4] = [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// `90d`, `12h`, `30m`, ... in seconds.
+fn parse_age(age: &str) -> Option<i64> {
+    let unit = age.chars().last()?;
+    let (_, seconds) = UNITS.iter().find(|(name, _)| *name == unit)?;
+    let number: i64 = age[..age.len() - 1].parse().ok().filter(|n| *n > 0)?;
+    number.checked_mul(*seconds)
+}
+
+/// Seconds in the largest unit that divides them.
+pub fn format_age(seconds: i64) -> String {
+    let (unit, size) = UNITS
+        .iter()
+        .find(|(_, size)| seconds % size == 0)
+        .unwrap_or(&('s', 1));
+    format!("{}{}", seconds / size, unit)
+}
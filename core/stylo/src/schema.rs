@@ -0,0 +1,338 @@
+//! Schema versioning.
+//!
+//! The schema version is kept in `PRAGMA user_version`. Every process
+//! opening the database applies the migrations it is missing, in order and
+//! in a single transaction, and refuses to touch a database written by a
+//! newer stylo. New schema changes are appended to `MIGRATIONS`; existing
+//! entries must never change.
+
+use crate::db;
+use crate::severity;
+use rusqlite::{Connection, OpenFlags, Result, params};
+use std::process;
+
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+/// Migration N brings the schema from version N - 1 to N.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Baseline: bring unversioned databases up to the full schema",
+        apply: baseline,
+    },
+    Migration {
+        description: "Index logs by source, level and timestamp",
+        apply: query_indexes,
+    },
+    Migration {
+        description: "Store retention rules, starting with the 24 hour default",
+        apply: retention_rules,
+    },
+    Migration {
+        description: "Count losses apart from the entries reporting them",
+        apply: counters,
+    },
+    Migration {
+        description: "Index logs by receive time, which retention goes by",
+        apply: received_index,
+    },
+];
+
+/// Columns added to `logs` after the original five, before the schema was
+/// versioned. The baseline migration appends whichever are missing.
+const LOG_COLUMNS: &[(&str, &str)] = &[
+    ("facility", "TEXT"),
+    ("hostname", "TEXT"),
+    ("app_name", "TEXT"),
+    ("procid", "TEXT"),
+    ("msgid", "TEXT"),
+    ("structured_data", "TEXT"),
+    ("origin", "TEXT"),
+    ("peer_subject", "TEXT"),
+    ("kmsg_seq", "INTEGER"),
+    ("monotonic_usec", "INTEGER"),
+    ("pid", "INTEGER"),
+    ("uid", "INTEGER"),
+    ("gid", "INTEGER"),
+    ("comm", "TEXT"),
+    ("exe", "TEXT"),
+    ("cgroup", "TEXT"),
+    ("container", "TEXT"),
+    ("stream", "TEXT"),
+    ("truncated", "INTEGER"),
+    ("original_length", "INTEGER"),
+    ("line_count", "INTEGER"),
+    ("level", "INTEGER"),
+    ("time_usec", "INTEGER"),
+    ("received_usec", "INTEGER"),
+    ("boottime_usec", "INTEGER"),
+    ("boot", "INTEGER"),
+    ("unsynced", "INTEGER"),
+    ("original_received_usec", "INTEGER"),
+];
+
+/// Columns added to `boots` before the schema was versioned.
+const BOOT_COLUMNS: &[(&str, &str)] = &[("clock_synced_usec", "INTEGER")];
+
+/// The schema version this stylo writes.
+pub fn latest() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+fn version(conn: &Connection) -> Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Refuse databases from a newer stylo; its writes would not fit them.
+fn check_not_newer(version: u32) {
+    if version > latest() {
+        eprintln!(
+            "Database {} has schema version {}, this stylo only knows up to {}",
+            db::get_db_path(),
+            version,
+            latest()
+        );
+        process::exit(1);
+    }
+}
+
+/// Apply all pending migrations. Returns the descriptions of the ones
+/// that ran.
+pub fn migrate(conn: &Connection) -> Result<Vec<&'static str>> {
+    // Up to date: the common case for every oneshot call, no lock needed
+    let current = version(conn)?;
+    check_not_newer(current);
+    if current == latest() {
+        return Ok(Vec::new());
+    }
+
+    // The write lock keeps concurrent first opens from migrating twice
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    let result = apply_pending(conn);
+    match result {
+        Ok(_) => conn.execute_batch("COMMIT")?,
+        Err(_) => {
+            let _ = conn.execute_batch("ROLLBACK");
+        }
+    }
+    result
+}
+
+fn apply_pending(conn: &Connection) -> Result<Vec<&'static str>> {
+    // Another process may have migrated while we waited for the lock
+    let current = version(conn)?;
+    check_not_newer(current);
+
+    let mut applied = Vec::new();
+    for migration in &MIGRATIONS[current as usize..] {
+        (migration.apply)(conn)?;
+        applied.push(migration.description);
+    }
+    conn.pragma_update(None, "user_version", latest())?;
+    Ok(applied)
+}
+
+/// `stylo migrate [--dry-run]`
+pub fn run_migrate(args: &[String]) -> Result<()> {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => {
+            crate::print_usage();
+            process::exit(1);
+        }
+    };
+
+    let path = db::get_db_path();
+    if dry_run {
+        // Read-only, so a dry run never creates or changes the file
+        let current = match Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+            Ok(conn) => version(&conn)?,
+            Err(_) => 0,
+        };
+        check_not_newer(current);
+        println!("{}: schema version {}, latest {}", path, current, latest());
+        for (n, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+            println!("  would apply {}: {}", n + 1, migration.description);
+        }
+        return Ok(());
+    }
+
+    let conn = db::init_db()?;
+    println!("{}: schema version {}", path, version(&conn)?);
+    Ok(())
+}
+
+/// Version 1. Databases from before schema versioning come in many
+/// shapes, from the original five columns onwards, so every step here is
+/// idempotent.
+fn baseline(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS logs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+            source TEXT NOT NULL,
+            severity TEXT NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+    if add_missing_columns(conn, "logs", LOG_COLUMNS)?.contains(&"level") {
+        backfill_levels(conn)?;
+    }
+
+    // Boot sessions, see boot.rs
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS boots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            boot_id TEXT NOT NULL UNIQUE,
+            started_usec INTEGER NOT NULL,
+            kernel TEXT,
+            os_name TEXT,
+            os_version TEXT
+        );
+        CREATE INDEX IF NOT EXISTS logs_boot ON logs (boot, id);
+        CREATE VIEW IF NOT EXISTS boot_list AS
+            SELECT b.*,
+                   1 - ROW_NUMBER() OVER (ORDER BY b.id DESC) AS offset,
+                   (SELECT COUNT(*) FROM logs WHERE logs.boot = b.id) AS entries
+            FROM boots b;",
+    )?;
+    add_missing_columns(conn, "boots", BOOT_COLUMNS)?;
+
+    // Last kernel record imported per boot, see kmsg.rs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS kmsg_state (
+            boot_id TEXT PRIMARY KEY,
+            last_seq INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Inode and read position of followed files, see tail.rs
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_state (
+            path TEXT PRIMARY KEY,
+            inode INTEGER NOT NULL,
+            offset INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Extra fields of structured datagrams, see json.rs
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS log_fields (
+            log_id INTEGER NOT NULL REFERENCES logs(id) ON DELETE CASCADE,
+            key TEXT NOT NULL,
+            value,
+            PRIMARY KEY (log_id, key)
+        ) WITHOUT ROWID;
+        CREATE INDEX IF NOT EXISTS log_fields_key_value ON log_fields (key, value);",
+    )?;
+
+    // Most entries come from the host, so only index container rows
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS logs_container ON logs (container, id)
+         WHERE container IS NOT NULL",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 2. Filters by source or level walk `(column, id)` so they come
+/// back in insertion order and combine with an `id` lower bound; the
+/// timestamp index serves time ranges.
+fn query_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS logs_source ON logs (source, id);
+        CREATE INDEX IF NOT EXISTS logs_level ON logs (level, id);
+        CREATE INDEX IF NOT EXISTS logs_timestamp ON logs (timestamp);",
+    )
+}
+
+/// Version 3. Rules applied by `stylo -c`, see retention.rs. The default
+/// rule keeps the cutoff that was hard-coded before.
+fn retention_rules(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE retention_rules (
+            id INTEGER PRIMARY KEY,
+            source TEXT,
+            level_min INTEGER,
+            level_max INTEGER,
+            keep_seconds INTEGER NOT NULL
+        );
+        INSERT INTO retention_rules (keep_seconds) VALUES (86400);",
+    )
+}
+
+/// Version 4. Totals shown by `stylo status`, see status.rs. They start
+/// from the reports still in the database.
+fn counters(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE counters (
+            name TEXT NOT NULL,
+            boot INTEGER NOT NULL,
+            value INTEGER NOT NULL,
+            PRIMARY KEY (name, boot)
+        );
+        INSERT INTO counters (name, boot, value)
+        SELECT f.key, IFNULL(l.boot, 0), SUM(f.value)
+        FROM log_fields f JOIN logs l ON l.id = f.log_id
+        WHERE f.key IN ('lost', 'dropped', 'deleted') AND l.source = 'stylo'
+        GROUP BY f.key, IFNULL(l.boot, 0);",
+    )
+}
+
+/// Version 5. Retention rules compare `received_usec` rather than the
+/// producer's `timestamp`, see retention.rs.
+fn received_index(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS logs_received ON logs (received_usec)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Returns the names of the columns that were added.
+fn add_missing_columns(
+    conn: &Connection,
+    table: &str,
+    columns: &[(&'static str, &str)],
+) -> Result<Vec<&'static str>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let existing = stmt
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut added = Vec::new();
+    for (name, kind) in columns {
+        if !existing.iter().any(|c| c == name) {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, kind),
+                [],
+            )?;
+            added.push(*name);
+        }
+    }
+    Ok(added)
+}
+
+/// Normalize the severities of entries written before `level` existed.
+fn backfill_levels(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT DISTINCT severity FROM logs")?;
+    let severities = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    for old in severities {
+        if let Some(level) = severity::level(&old) {
+            conn.execute(
+                "UPDATE logs SET severity = ?1, level = ?2 WHERE severity = ?3",
+                params![severity::name(level), level, old],
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Full-text search over messages.
+//!
+//! `logs_fts` is an FTS5 index with `logs` as its external content, so the
+//! message text is not stored twice. Triggers keep it in sync with every
+//! insert and delete, whichever process writes, including retention. The
+//! index is optional: the daemon creates it on start when
+//! `full_text_search` is on and drops it when it is off, which saves its
+//! disk space and the per-insert work on small systems.
+
+use crate::db;
+use rusqlite::{Connection, Result};
+use std::process;
+
+/// Results shown unless `-n` says otherwise
+const DEFAULT_LIMIT: usize = 50;
+
+/// Create or drop the index to match the configuration.
+pub fn configure(conn: &Connection, enabled: bool) -> Result<()> {
+    if enabled == is_enabled(conn)? {
+        return Ok(());
+    }
+    if enabled {
+        println!("Building full-text index");
+        conn.execute_batch(
+            "BEGIN IMMEDIATE;
+            CREATE VIRTUAL TABLE logs_fts USING fts5 (
+                message, content = 'logs', content_rowid = 'id'
+            );
+            CREATE TRIGGER logs_fts_insert AFTER INSERT ON logs BEGIN
+                INSERT INTO logs_fts (rowid, message) VALUES (new.id, new.message);
+            END;
+            CREATE TRIGGER logs_fts_delete AFTER DELETE ON logs BEGIN
+                INSERT INTO logs_fts (logs_fts, rowid, message)
+                VALUES ('delete', old.id, old.message);
+            END;
+            CREATE TRIGGER logs_fts_update AFTER UPDATE OF message ON logs BEGIN
+                INSERT INTO logs_fts (logs_fts, rowid, message)
+                VALUES ('delete', old.id, old.message);
+                INSERT INTO logs_fts (rowid, message) VALUES (new.id, new.message);
+            END;
+            INSERT INTO logs_fts (logs_fts) VALUES ('rebuild');
+            COMMIT;",
+        )
+    } else {
+        println!("Dropping full-text index");
+        conn.execute_batch(
+            "BEGIN IMMEDIATE;
+            DROP TRIGGER logs_fts_insert;
+            DROP TRIGGER logs_fts_delete;
+            DROP TRIGGER logs_fts_update;
+            DROP TABLE logs_fts;
+            COMMIT;",
+        )
+    }
+}
+
+pub fn is_enabled(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'logs_fts')",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Merge the index segments left behind by inserts and deletes.
+pub fn optimize(conn: &Connection) -> Result<()> {
+    if is_enabled(conn)? {
+        conn.execute("INSERT INTO logs_fts (logs_fts) VALUES ('optimize')", [])?;
+    }
+    Ok(())
+}
+
+/// `stylo search [-n LIMIT] TERMS...`: best matches first, with the
+/// matching terms highlighted.
+pub fn run(args: &[String]) -> Result<()> {
+    let (limit, terms) = match args {
+        [flag, limit, terms @ ..] if flag == "-n" => match limit.parse() {
+            Ok(limit) => (limit, terms),
+            Err(_) => {
+                eprintln!("Invalid limit: {}", limit);
+                process::exit(1);
+            }
+        },
+        terms => (DEFAULT_LIMIT, terms),
+    };
+    if terms.is_empty() {
+        crate::print_usage();
+        process::exit(1);
+    }
+
+    let conn = db::init_db()?;
+    if !is_enabled(&conn)? {
+        eprintln!("Full-text search is off, set full_text_search = on and restart the daemon");
+        process::exit(1);
+    }
+
+    // Bold on a terminal, brackets when piped
+    let (open, close) = if unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1 {
+        ("\x1b[1m", "\x1b[0m")
+    } else {
+        ("[", "]")
+    };
+    let mut stmt = conn.prepare(
+        "SELECT logs.timestamp, logs.source, logs.severity,
+                snippet(logs_fts, 0, ?1, ?2, '…', 16)
+         FROM logs_fts JOIN logs ON logs.id = logs_fts.rowid
+         WHERE logs_fts MATCH ?3
+         ORDER BY rank LIMIT ?4",
+    )?;
+    let rows = stmt.query_map((open, close, terms.join(" "), limit as i64), |row| {
+        Ok(format!(
+            "{} {} {}: {}",
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    });
+    // Syntax errors in the query only surface once it runs
+    let result = rows.and_then(|rows| {
+        for row in rows {
+            println!("{}", row?);
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        eprintln!("Invalid search query: {}", e);
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Severity model: the eight RFC 5424 levels.
+//!
+//! Producers spell severities in many ways (`ERR`, `error`, `Warn`, `4`).
+//! Known spellings are normalized to the canonical name and stored with the
+//! numeric `level`, 0 (`EMERG`) to 7 (`DEBUG`), so "warning or worse" is
+//! simply `level <= 4`. Anything else is handled by the `unknown_severity`
+//! policy.
+
+use crate::config::UnknownSeverity;
+use crate::entry::Entry;
+
+const NAMES: [&str; 8] = [
+    "EMERG", "ALERT", "CRIT", "ERROR", "WARNING", "NOTICE", "INFO", "DEBUG",
+];
+
+/// Rank of entries whose unknown severity was kept, where one is needed
+pub const DEFAULT_LEVEL: u8 = 6;
+
+pub fn name(level: u8) -> &'static str {
+    NAMES[(level & 7) as usize]
+}
+
+/// Counts per level like `2 INFO, 1 DEBUG`, most severe first.
+pub fn summary(counts: &[u64; 8]) -> String {
+    let by_level: Vec<String> = (0..8)
+        .filter(|level| counts[*level] > 0)
+        .map(|level| format!("{} {}", counts[level], name(level as u8)))
+        .collect();
+    by_level.join(", ")
+}
+
+/// Level of a severity name, common alias or number, ignoring case.
+pub fn level(severity: &str) -> Option<u8> {
+    match severity.trim().to_ascii_uppercase().as_str() {
+        "0" | "EMERG" | "EMERGENCY" | "PANIC" => Some(0),
+        "1" | "ALERT" => Some(1),
+        "2" | "CRIT" | "CRITICAL" | "FATAL" => Some(2),
+        "3" | "ERR" | "ERROR" => Some(3),
+        "4" | "WARN" | "WARNING" => Some(4),
+        "5" | "NOTICE" => Some(5),
+        "6" | "INFO" | "INFORMATIONAL" => Some(6),
+        "7" | "DEBUG" | "TRACE" => Some(7),
+        _ => None,
+    }
+}
+
+/// Replace the entry's severity by its canonical name and set `level`.
+/// Returns an explanation if the policy rejects an unknown severity.
+pub fn normalize(entry: &mut Entry, policy: UnknownSeverity) -> Result<(), String> {
+    let level = match (level(&entry.severity), policy) {
+        (Some(level), _) | (None, UnknownSeverity::Map(level)) => level,
+        (None, UnknownSeverity::Keep) => return Ok(()),
+        (None, UnknownSeverity::Reject) => {
+            return Err(format!(
+                "Rejected entry from '{}' with unknown severity '{}'",
+                entry.source, entry.severity
+            ));
+        }
+    };
+    entry.severity = name(level).to_string();
+    entry.level = Some(level);
+    Ok(())
+}
@@ -0,0 +1,444 @@
+//! The ingestion path shared by every input: severities are normalized,
+//! entries are written to the database and, if a TLS forwarder is
+//! configured, handed on to it.
+//!
+//! Inputs only queue their entries; a single writer thread persists them,
+//! so a slow fsync or a long reader never stalls reception. The writer
+//! commits in batches (group commit): a batch is written once it holds
+//! `batch_size` entries or its first entry has waited `batch_latency_ms`.
+//! One WAL commit per batch instead of per entry is what lets bursts
+//! through; the price is that a crash loses the batch being collected.
+//! `flush` writes it out for a clean shutdown.
+//!
+//! Inputs that resume where they stopped queue their read position as
+//! well. It is written in the transaction that holds the last entry read
+//! before it, so the saved position never runs ahead of what is stored:
+//! a crash or a failed batch makes the input read those entries again
+//! rather than skip them.
+//!
+//! The queue holds `queue_size` entries. When the writer falls that far
+//! behind, the `overflow` policy picks the entry to drop; drops are counted
+//! per severity and recorded as a `WARNING` from `stylo` every ten seconds.
+//!
+//! A commit that fails, e.g. on a full disk, is retried `COMMIT_ATTEMPTS`
+//! times with growing pauses. If it still fails, the batch is given up and
+//! its entries are recorded as dropped like the ones the queue had no room
+//! for. So are single entries the database refuses; they are left out and
+//! the rest of the batch is committed.
+//!
+//! After each commit the writer also enforces the size limit of the
+//! database, see quota.rs.
+
+use crate::config::{Config, Durability, Overflow, UnknownSeverity};
+use crate::cred;
+use crate::db;
+use crate::entry::Entry;
+use crate::kmsg;
+use crate::quota;
+use crate::severity;
+use crate::tail;
+use rusqlite::{Connection, Result, Transaction, TransactionBehavior};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often dropped entries are recorded
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Attempts at committing a batch before it is given up
+const COMMIT_ATTEMPTS: u32 = 5;
+/// Pause before the first retry, doubled for each one after it
+const COMMIT_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Clone)]
+pub struct Sink {
+    queue: Arc<Queue>,
+    forward: Option<SyncSender<Entry>>,
+    unknown_severity: UnknownSeverity,
+}
+
+struct Queue {
+    state: Mutex<State>,
+    /// Signalled when entries or flush requests arrive
+    ready: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+/// How far an input has read, see `Sink::save_position`.
+pub enum Position {
+    /// Inode and offset of a followed file, for `file_state`
+    File {
+        path: String,
+        inode: u64,
+        offset: u64,
+    },
+    /// Last kernel record imported during a boot, for `kmsg_state`
+    Kmsg { boot_id: String, seq: i64 },
+}
+
+impl Position {
+    fn same_input(&self, other: &Position) -> bool {
+        match (self, other) {
+            (Position::File { path, .. }, Position::File { path: other, .. }) => path == other,
+            (Position::Kmsg { boot_id, .. }, Position::Kmsg { boot_id: other, .. }) => {
+                boot_id == other
+            }
+            _ => false,
+        }
+    }
+
+    fn save(&self, conn: &Connection) -> Result<()> {
+        match self {
+            Position::File {
+                path,
+                inode,
+                offset,
+            } => tail::save_state(conn, path, (*inode, *offset)),
+            Position::Kmsg { boot_id, seq } => kmsg::save_last_seq(conn, boot_id, *seq),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// One queue per level, so the least severe entries are found without
+    /// a search; the sequence numbers restore the order of arrival
+    levels: [VecDeque<(u64, Entry)>; 8],
+    len: usize,
+    next_seq: u64,
+    /// Positions with the sequence number of the first entry queued after
+    /// them
+    positions: Vec<(u64, Position)>,
+    /// Entries dropped per level since the last report
+    dropped: [u64; 8],
+    /// Callers of `flush` waiting for the queue to be written
+    flushes: Vec<SyncSender<()>>,
+}
+
+impl Sink {
+    /// Start the writer thread. Clones of the sink share it.
+    pub fn open(cfg: &Config, forward: Option<SyncSender<Entry>>) -> Result<Sink> {
+        let conn = db::init_db()?;
+        set_durability(&conn, cfg.durability)?;
+
+        let queue = Arc::new(Queue {
+            state: Mutex::new(State::default()),
+            ready: Condvar::new(),
+            capacity: cfg.queue_size(),
+            overflow: cfg.overflow,
+        });
+        let writer_queue = queue.clone();
+        let (batch_size, latency) = (cfg.batch_size(), cfg.batch_latency());
+        let limit = quota::limit(cfg);
+        thread::spawn(move || write_batches(conn, &writer_queue, batch_size, latency, limit));
+        Ok(Sink {
+            queue,
+            forward,
+            unknown_severity: cfg.unknown_severity,
+        })
+    }
+
+    pub fn store(&self, entry: &Entry) {
+        self.store_batch(vec![entry.clone()]);
+    }
+
+    /// Queue several entries at once, taking the queue lock only once.
+    pub fn store_batch(&self, entries: Vec<Entry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let entries: Vec<Entry> = entries
+            .into_iter()
+            .map(|entry| self.prepare(entry))
+            .collect();
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        for entry in entries {
+            state.push(entry, queue.capacity, queue.overflow);
+        }
+        drop(state);
+        queue.ready.notify_one();
+    }
+
+    fn prepare(&self, mut entry: Entry) -> Entry {
+        entry.stamp_received();
+        if let Err(reason) = severity::normalize(&mut entry, self.unknown_severity) {
+            // The rejection itself has a known severity
+            entry = cred::rejection(&entry, &reason);
+            let _ = severity::normalize(&mut entry, self.unknown_severity);
+        }
+        if let Some(forward) = &self.forward {
+            // Never block ingestion on a slow or unreachable collector
+            let _ = forward.try_send(entry.clone());
+        }
+        entry
+    }
+
+    /// Queue the position of an input, to be written along with the
+    /// entries stored before it.
+    pub fn save_position(&self, position: Position) {
+        let queue = &self.queue;
+        queue.state.lock().unwrap().save(position);
+        queue.ready.notify_one();
+    }
+
+    /// Wait until everything stored so far is committed.
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        self.queue.state.lock().unwrap().flushes.push(ack);
+        self.queue.ready.notify_one();
+        let _ = done.recv();
+    }
+}
+
+/// Sync commits on `conn` as `durability` asks.
+pub fn set_durability(conn: &Connection, durability: Durability) -> Result<()> {
+    let synchronous = match durability {
+        Durability::Full => "FULL",
+        Durability::Normal => "NORMAL",
+        Durability::Off => "OFF",
+    };
+    conn.pragma_update(None, "synchronous", synchronous)
+}
+
+impl State {
+    fn push(&mut self, entry: Entry, capacity: usize, overflow: Overflow) {
+        let level = entry.level.unwrap_or(severity::DEFAULT_LEVEL) as usize;
+        if self.len >= capacity {
+            let victim = match overflow {
+                Overflow::Oldest => self.oldest(),
+                Overflow::Newest => None,
+                Overflow::Severity => (level + 1..8)
+                    .rev()
+                    .find(|level| !self.levels[*level].is_empty()),
+            };
+            let Some(victim) = victim else {
+                self.dropped[level] += 1;
+                return;
+            };
+            self.levels[victim].pop_front();
+            self.dropped[victim] += 1;
+            self.len -= 1;
+        }
+        self.levels[level].push_back((self.next_seq, entry));
+        self.next_seq += 1;
+        self.len += 1;
+    }
+
+    fn save(&mut self, position: Position) {
+        // Nothing was stored since the last position of the input, so that
+        // one is out of date already
+        if let Some((_, last)) = self
+            .positions
+            .iter_mut()
+            .rev()
+            .take_while(|(seq, _)| *seq == self.next_seq)
+            .find(|(_, last)| last.same_input(&position))
+        {
+            *last = position;
+            return;
+        }
+        self.positions.push((self.next_seq, position));
+    }
+
+    /// The positions whose entries have all left the queue.
+    fn take_positions(&mut self) -> Vec<Position> {
+        let first = (0..8)
+            .filter_map(|level| Some(self.levels[level].front()?.0))
+            .min()
+            .unwrap_or(self.next_seq);
+        let ready = self.positions.partition_point(|(seq, _)| *seq <= first);
+        self.positions
+            .drain(..ready)
+            .map(|(_, position)| position)
+            .collect()
+    }
+
+    /// The level whose first entry arrived before all others.
+    fn oldest(&self) -> Option<usize> {
+        (0..8)
+            .filter_map(|level| Some((self.levels[level].front()?.0, level)))
+            .min()
+            .map(|(_, level)| level)
+    }
+
+    fn pop(&mut self) -> Option<Entry> {
+        let level = self.oldest()?;
+        self.len -= 1;
+        self.levels[level].pop_front().map(|(_, entry)| entry)
+    }
+}
+
+/// The writer thread, running for the lifetime of the process. It also
+/// keeps the database within `limit` bytes, see quota.rs.
+fn write_batches(
+    conn: Connection,
+    queue: &Queue,
+    batch_size: usize,
+    latency: Duration,
+    limit: Option<u64>,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut positions = Vec::new();
+    // Entries lost per level and not recorded yet: dropped from the queue,
+    // and given up after failed commits
+    let mut dropped = [0; 8];
+    let mut failed = [0; 8];
+    let mut deadline: Option<Instant> = None;
+    let mut last_report = Instant::now();
+    loop {
+        let mut state = queue.state.lock().unwrap();
+        while state.len == 0 && state.positions.is_empty() && state.flushes.is_empty() {
+            // Sleep until the batch is due or the drops are to be reported
+            let report = state.dropped.iter().any(|n| *n > 0);
+            let wake = match (deadline, report.then(|| last_report + DROP_REPORT_INTERVAL)) {
+                (Some(deadline), Some(report)) => Some(deadline.min(report)),
+                (deadline, report) => deadline.or(report),
+            };
+            let Some(wake) = wake else {
+                state = queue.ready.wait(state).unwrap();
+                continue;
+            };
+            let now = Instant::now();
+            if now >= wake {
+                break;
+            }
+            state = queue.ready.wait_timeout(state, wake - now).unwrap().0;
+        }
+
+        while batch.len() < batch_size
+            && let Some(entry) = state.pop()
+        {
+            batch.push(entry);
+        }
+        positions.append(&mut state.take_positions());
+        let mut flushes = mem::take(&mut state.flushes);
+        if !flushes.is_empty() && state.len > 0 {
+            // Not everything fit into this batch; acknowledge after the rest
+            state.flushes = mem::take(&mut flushes);
+        }
+        let reporting = !flushes.is_empty() || last_report.elapsed() >= DROP_REPORT_INTERVAL;
+        if reporting && state.dropped.iter().any(|n| *n > 0) {
+            for (total, n) in dropped.iter_mut().zip(mem::take(&mut state.dropped)) {
+                *total += n;
+            }
+            last_report = Instant::now();
+        }
+        let flushing = !state.flushes.is_empty() || !flushes.is_empty();
+        drop(state);
+
+        let unreported = dropped.iter().chain(&failed).any(|n| *n > 0);
+        if batch.is_empty() && positions.is_empty() && !unreported {
+            for ack in flushes {
+                let _ = ack.send(());
+            }
+            continue;
+        }
+        let deadline_passed =
+            *deadline.get_or_insert_with(|| Instant::now() + latency) <= Instant::now();
+        if flushing || batch.len() >= batch_size || deadline_passed {
+            let stored = batch.len();
+            batch.extend(drop_report(&dropped, "write queue full"));
+            batch.extend(drop_report(&failed, "database write failed"));
+            match commit(&conn, &batch, &positions) {
+                Ok(refused) => {
+                    // Recorded with the next batch
+                    dropped = [0; 8];
+                    failed = refused;
+                }
+                Err(e) => {
+                    eprintln!("Gave up writing {} entries: {}", stored, e);
+                    // The reports are made again from the counts
+                    for entry in &batch[..stored] {
+                        failed[entry.level.unwrap_or(severity::DEFAULT_LEVEL) as usize] += 1;
+                    }
+                }
+            }
+            batch.clear();
+            positions.clear();
+            deadline = None;
+            if let Some(limit) = limit {
+                quota::check(&conn, limit);
+            }
+        }
+        for ack in flushes {
+            let _ = ack.send(());
+        }
+    }
+}
+
+/// The entry recording what was dropped for `reason` since the last
+/// report, if anything.
+fn drop_report(dropped: &[u64; 8], reason: &str) -> Option<Entry> {
+    let total: u64 = dropped.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let mut entry = Entry::new(
+        "stylo",
+        "WARNING",
+        &format!(
+            "Dropped {} entries, {} ({})",
+            total,
+            reason,
+            severity::summary(dropped)
+        ),
+    );
+    entry.level = Some(4);
+    entry
+        .fields
+        .push(("dropped".to_string(), Value::from(total)));
+    entry.counter = Some(("dropped", total));
+    entry.stamp_received();
+    Some(entry)
+}
+
+/// Write a batch and the positions queued with it, retrying with growing
+/// pauses while the database fails. Returns the entries left out per level.
+fn commit(conn: &Connection, batch: &[Entry], positions: &[Position]) -> Result<[u64; 8]> {
+    let mut delay = COMMIT_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match write(conn, batch, positions) {
+            Err(e) if attempt < COMMIT_ATTEMPTS => {
+                eprintln!(
+                    "Could not write {} entries, retrying in {} ms: {}",
+                    batch.len(),
+                    delay.as_millis(),
+                    e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Write a batch in one transaction, as the writer thread does. Returns
+/// the entries that could not be inserted and were left out, per level.
+pub fn write(conn: &Connection, batch: &[Entry], positions: &[Position]) -> Result<[u64; 8]> {
+    let mut tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let mut refused = [0; 8];
+    for entry in batch {
+        // A bad entry must not take the rest of the batch with it
+        let savepoint = tx.savepoint()?;
+        match db::insert(&savepoint, entry) {
+            Ok(()) => savepoint.commit()?,
+            Err(e) => {
+                eprintln!("Could not write entry from {}: {}", entry.source, e);
+                refused[entry.level.unwrap_or(severity::DEFAULT_LEVEL) as usize] += 1;
+            }
+        }
+    }
+    for position in positions {
+        position.save(&tx)?;
+    }
+    tx.commit()?;
+    Ok(refused)
+}
@@ -0,0 +1,80 @@
+//! `stylo status`: what the database holds and what never made it there.
+//!
+//! Losses are recorded as entries from `stylo` as they happen, and added
+//! up in `counters` in the same transaction, so the totals survive
+//! restarts as well as the deletion of the reports by retention or the
+//! size limit: `lost` counts datagrams the kernel dropped because a
+//! socket's receive buffer was full (see datagram.rs), `dropped` entries
+//! the writer's queue had no room for or could not commit (see sink.rs)
+//! and `deleted` entries removed to keep the database within its size
+//! limit (see quota.rs).
+
+use crate::boot;
+use crate::config::Config;
+use crate::db;
+use crate::quota;
+use crate::search;
+use rusqlite::{Connection, Result};
+
+pub fn run() -> Result<()> {
+    let conn = db::init_db()?;
+    let boot_id = &boot::current().id;
+
+    println!("Database: {}", db::get_db_path());
+    let used = quota::used(&conn)? as f64 / (1 << 20) as f64;
+    match quota::limit(&Config::load()) {
+        Some(limit) => println!(
+            "Size: {:.1} MiB in use, limit {:.1} MiB",
+            used,
+            limit as f64 / (1 << 20) as f64
+        ),
+        None => println!("Size: {:.1} MiB in use, no limit", used),
+    }
+    let (total, this_boot) = count(&conn, boot_id)?;
+    println!("Entries: {} ({} this boot)", total, this_boot);
+    let full_text = if search::is_enabled(&conn)? {
+        "on"
+    } else {
+        "off"
+    };
+    println!("Full-text search: {}", full_text);
+    let (total, this_boot) = counter(&conn, "lost", boot_id)?;
+    println!(
+        "Messages lost, receive buffer full: {} ({} this boot)",
+        total, this_boot
+    );
+    let (total, this_boot) = counter(&conn, "dropped", boot_id)?;
+    println!(
+        "Entries dropped, queue full or write failed: {} ({} this boot)",
+        total, this_boot
+    );
+    let (total, this_boot) = counter(&conn, "deleted", boot_id)?;
+    println!(
+        "Entries deleted, size limit: {} ({} this boot)",
+        total, this_boot
+    );
+    Ok(())
+}
+
+/// Entries overall and in the boot `boot_id`.
+fn count(conn: &Connection, boot_id: &str) -> Result<(i64, i64)> {
+    conn.query_row(
+        "SELECT COUNT(*),
+                COUNT(*) FILTER (WHERE boot = (SELECT id FROM boots WHERE boot_id = ?1))
+         FROM logs",
+        [boot_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+/// The counter `name`, overall and in the boot `boot_id`.
+fn counter(conn: &Connection, name: &str, boot_id: &str) -> Result<(i64, i64)> {
+    conn.query_row(
+        "SELECT IFNULL(SUM(value), 0),
+                IFNULL(SUM(value) FILTER (
+                    WHERE boot = (SELECT id FROM boots WHERE boot_id = ?2)), 0)
+         FROM counters WHERE name = ?1",
+        (name, boot_id),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
@@ -0,0 +1,245 @@
+//! Decoding of syslog frames as written to `/dev/log` by libc's `syslog(3)`,
+//! busybox `logger` and most third-party daemons.
+//!
+//! Both the modern RFC 5424 format (`<PRI>1 TIMESTAMP HOST APP PROCID MSGID
+//! [SD] MSG`) and the classic BSD format from RFC 3164 (`<PRI>Mmm dd hh:mm:ss
+//! HOST TAG[PID]: MSG`) are understood.
+
+use crate::clock;
+use crate::entry::{Entry, EventTime};
+use crate::severity;
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub fn facility_name(code: u8) -> Option<&'static str> {
+    FACILITIES.get(code as usize).copied()
+}
+
+/// Format an entry as an RFC 5424 frame for forwarding. `hostname` is used
+/// for entries that did not arrive with one. The timestamp is the event
+/// time, or the receive time if the producer gave none.
+pub fn format_rfc5424(entry: &Entry, hostname: &str) -> String {
+    let facility = entry
+        .facility
+        .as_deref()
+        .and_then(|f| FACILITIES.iter().position(|name| *name == f))
+        .unwrap_or(1);
+    // Unknown severities that were kept go out as INFO
+    let level = entry
+        .level
+        .or_else(|| severity::level(&entry.severity))
+        .unwrap_or(6);
+    let pri = facility * 8 + level as usize;
+    let time = match &entry.event_time {
+        Some(EventTime::Usec(usec)) => Some(*usec),
+        Some(EventTime::Text(text)) => clock::parse_rfc3339(text).or(entry.received_usec),
+        None => entry.received_usec,
+    };
+
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        pri,
+        time.map_or("-".to_string(), clock::format_rfc3339),
+        header_field(entry.hostname.as_deref().unwrap_or(hostname)),
+        header_field(entry.app_name.as_deref().unwrap_or(&entry.source)),
+        header_field(entry.procid.as_deref().unwrap_or("")),
+        header_field(entry.msgid.as_deref().unwrap_or("")),
+        entry.structured_data.as_deref().unwrap_or("-"),
+        entry.message
+    )
+}
+
+/// A header field as RFC 5424 allows it: printable ASCII without spaces,
+/// `-` when empty. Anything else becomes `_`.
+fn header_field(value: &str) -> String {
+    if value.is_empty() {
+        return "-".to_string();
+    }
+    value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .collect()
+}
+
+/// Parse a frame starting with `<PRI>`. Returns `None` if the priority
+/// is malformed, in which case the caller treats the frame as plain text.
+pub fn parse(frame: &str) -> Option<Entry> {
+    let (pri, rest) = parse_pri(frame)?;
+
+    let mut entry = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest),
+        None => parse_rfc3164(rest),
+    };
+    entry.severity = severity::name(pri).to_string();
+    entry.facility = facility_name(pri >> 3).map(str::to_string);
+    entry.source = entry
+        .app_name
+        .clone()
+        .unwrap_or_else(|| "syslog".to_string());
+    Some(entry)
+}
+
+fn parse_pri(frame: &str) -> Option<(u8, &str)> {
+    let end = frame.find('>')?;
+    let digits = &frame[1..end];
+    if digits.is_empty() || digits.len() > 3 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let pri: u8 = digits.parse().ok()?;
+    if pri > 191 {
+        return None;
+    }
+    Some((pri, &frame[end + 1..]))
+}
+
+fn parse_rfc5424(rest: &str) -> Entry {
+    let mut fields = rest.splitn(6, ' ');
+    let event_time = fields
+        .next()
+        .and_then(clock::parse_rfc3339)
+        .map(EventTime::Usec);
+    let hostname = fields.next().and_then(nil);
+    let app_name = fields.next().and_then(nil);
+    let procid = fields.next().and_then(nil);
+    let msgid = fields.next().and_then(nil);
+    let (structured_data, message) = split_structured_data(fields.next().unwrap_or("-"));
+
+    Entry {
+        message: message.trim_start_matches('\u{feff}').to_string(),
+        event_time,
+        hostname,
+        app_name,
+        procid,
+        msgid,
+        structured_data,
+        ..Default::default()
+    }
+}
+
+/// RFC 5424 uses `-` for absent header fields.
+fn nil(field: &str) -> Option<String> {
+    match field {
+        "" | "-" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+/// Split `[id k="v"][id2 ...] MSG` into the structured data elements and the
+/// message. Brackets inside quoted parameter values may be escaped with `\`.
+fn split_structured_data(s: &str) -> (Option<String>, &str) {
+    if let Some(msg) = s.strip_prefix('-') {
+        return (None, msg.strip_prefix(' ').unwrap_or(msg));
+    }
+    if !s.starts_with('[') {
+        return (None, s);
+    }
+
+    let bytes = s.as_bytes();
+    let mut in_quotes = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if in_quotes => i += 1,
+            b'"' => in_quotes = !in_quotes,
+            b']' if !in_quotes && bytes.get(i + 1) != Some(&b'[') => {
+                let msg = &s[i + 1..];
+                return (
+                    Some(s[..=i].to_string()),
+                    msg.strip_prefix(' ').unwrap_or(msg),
+                );
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    // Unterminated element: keep everything as message text
+    (None, s)
+}
+
+fn parse_rfc3164(rest: &str) -> Entry {
+    let rest = skip_rfc3164_timestamp(rest);
+    let mut entry = Entry::default();
+
+    // Local senders (libc, busybox) omit the hostname, so the first word is
+    // only a hostname if the word after it is a tag.
+    let mut words = rest.splitn(3, ' ');
+    let first = words.next().unwrap_or("");
+    let second = words.next();
+
+    let (tag, message) = if let Some(tag) = parse_tag(first) {
+        (Some(tag), rest[first.len()..].trim_start_matches(' '))
+    } else if let Some(tag) = second.and_then(parse_tag) {
+        entry.hostname = Some(first.to_string());
+        (Some(tag), words.next().unwrap_or(""))
+    } else {
+        (None, rest)
+    };
+
+    if let Some((name, pid)) = tag {
+        entry.app_name = Some(name.to_string());
+        entry.procid = pid.map(|p| p.to_string());
+    }
+    entry.message = message.to_string();
+    entry
+}
+
+/// Skip a leading `Mmm dd hh:mm:ss ` timestamp if present.
+fn skip_rfc3164_timestamp(rest: &str) -> &str {
+    let b = rest.as_bytes();
+    let looks_like_timestamp = b.len() >= 16
+        && rest.get(..3).is_some_and(|m| MONTHS.contains(&m))
+        && b[3] == b' '
+        && b[6] == b' '
+        && b[9] == b':'
+        && b[12] == b':'
+        && b[15] == b' ';
+
+    if looks_like_timestamp {
+        &rest[16..]
+    } else {
+        rest
+    }
+}
+
+/// Split a `name[pid]:` or `name:` tag into its parts.
+fn parse_tag(word: &str) -> Option<(&str, Option<&str>)> {
+    let tag = word.strip_suffix(':')?;
+    let (name, pid) = match tag.split_once('[') {
+        Some((name, pid)) => (name, Some(pid.strip_suffix(']')?)),
+        None => (tag, None),
+    };
+    if name.is_empty() || name.len() > 48 {
+        return None;
+    }
+    Some((name, pid))
+}
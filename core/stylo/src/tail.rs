@@ -0,0 +1,375 @@
+//! File input: follow log files written by programs that cannot log
+//! anywhere else.
+//!
+//! Every `tail_file` is read line by line and stored with its configured
+//! source and severity and the path in `origin`. The parent directories are
+//! watched with inotify, so appends, truncation, rename-rotation and
+//! recreation are noticed right away; a check every second covers anything
+//! the events miss. Inode and read offset are kept in `file_state`, so a
+//! restarted daemon neither repeats nor skips lines. While lines are held
+//! back to be joined (see multiline.rs), the saved offset stays at the
+//! first of them, so they are read again after a restart rather than lost.
+//!
+//! Files are read in chunks of `READ_CHUNK`. Of a line longer than
+//! `max_message_size`, only that much is kept in memory; the rest is
+//! skipped and the entry gets the full length in `original_length`.
+
+use crate::config::{Config, TailFile};
+use crate::db;
+use crate::entry::Entry;
+use crate::multiline::Joiner;
+use crate::sink::{Position, Sink};
+use rusqlite::{Connection, OptionalExtension, Result, params};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Longest wait for inotify before the files are checked anyway
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes read from a file at a time
+const READ_CHUNK: usize = 64 * 1024;
+
+struct Tailed {
+    spec: TailFile,
+    file: Option<File>,
+    inode: u64,
+    /// Bytes of the open file read so far
+    offset: u64,
+    /// Trailing bytes not terminated by a newline yet, at most
+    /// `max_message_size` of them
+    partial: Vec<u8>,
+    /// Length of the unterminated line, including the bytes skipped
+    partial_len: usize,
+    /// Offset of the line that started the entry pending in the joiner
+    pending_start: Option<u64>,
+    /// Inode and offset last queued for `file_state`
+    saved: Option<(u64, u64)>,
+}
+
+pub fn spawn(cfg: &Config, sink: Sink) -> Result<()> {
+    if cfg.tail_files.is_empty() {
+        return Ok(());
+    }
+    let state = db::init_db()?;
+
+    let inotify = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if inotify < 0 {
+        eprintln!(
+            "Could not set up inotify, polling files: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    let mut watched = HashSet::new();
+    let mut files = Vec::new();
+    for spec in &cfg.tail_files {
+        // Rotation replaces the file, so watch the directory it lives in
+        let dir = match Path::new(&spec.path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if inotify >= 0 && watched.insert(dir.clone()) {
+            watch(inotify, &dir);
+        }
+
+        let mut tailed = Tailed {
+            spec: spec.clone(),
+            file: None,
+            inode: 0,
+            offset: 0,
+            partial: Vec::new(),
+            partial_len: 0,
+            pending_start: None,
+            saved: None,
+        };
+        tailed.resume(load_state(&state, &spec.path)?);
+        println!("Stylo daemon following {}", spec.path);
+        files.push(tailed);
+    }
+
+    let joiner = Joiner::new(cfg);
+    let max_size = cfg.max_message_size();
+    thread::spawn(move || follow(inotify, files, sink, joiner, max_size));
+    Ok(())
+}
+
+fn watch(inotify: libc::c_int, dir: &Path) {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return;
+    };
+    let mask = libc::IN_MODIFY
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO;
+    if unsafe { libc::inotify_add_watch(inotify, path.as_ptr(), mask) } < 0 {
+        eprintln!(
+            "Could not watch {}: {}",
+            dir.display(),
+            io::Error::last_os_error()
+        );
+    }
+}
+
+fn follow(
+    inotify: libc::c_int,
+    mut files: Vec<Tailed>,
+    sink: Sink,
+    mut joiner: Joiner,
+    max_size: usize,
+) {
+    let timeout = if joiner.is_active() {
+        joiner.timeout().min(POLL_INTERVAL)
+    } else {
+        POLL_INTERVAL
+    };
+
+    loop {
+        for tailed in &mut files {
+            tailed.poll(&mut joiner, &sink, max_size);
+            tailed.save(&sink, &joiner);
+        }
+        for entry in joiner.expired() {
+            sink.store(&entry);
+        }
+        wait(inotify, timeout);
+    }
+}
+
+/// Block until a watched directory changes or `timeout` passed.
+fn wait(inotify: libc::c_int, timeout: Duration) {
+    if inotify < 0 {
+        thread::sleep(timeout);
+        return;
+    }
+    let mut pollfd = libc::pollfd {
+        fd: inotify,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) } > 0 {
+        // Which file changed does not matter, all of them are checked
+        let mut events = [0u8; 4096];
+        unsafe {
+            libc::read(
+                inotify,
+                events.as_mut_ptr() as *mut libc::c_void,
+                events.len(),
+            )
+        };
+    }
+}
+
+impl Tailed {
+    /// Continue at the saved offset if the file at the path is still the
+    /// one it was taken from, otherwise read it from the start.
+    fn resume(&mut self, saved: Option<(u64, u64)>) {
+        self.saved = saved;
+        let Ok(file) = File::open(&self.spec.path) else {
+            // Picked up once it appears
+            return;
+        };
+        let Ok(meta) = file.metadata() else {
+            return;
+        };
+        let offset = match saved {
+            Some((inode, offset)) if inode == meta.ino() && offset <= meta.len() => offset,
+            _ => 0,
+        };
+        self.open(file, meta.ino(), offset);
+    }
+
+    fn open(&mut self, mut file: File, inode: u64, offset: u64) {
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            eprintln!("Could not seek in {}: {}", self.spec.path, e);
+            return;
+        }
+        self.file = Some(file);
+        self.inode = inode;
+        self.offset = offset;
+        self.partial.clear();
+        self.partial_len = 0;
+        self.pending_start = None;
+    }
+
+    /// Open whatever file is at the path now, from the start.
+    fn reopen(&mut self) {
+        self.file = None;
+        if let Ok(file) = File::open(&self.spec.path)
+            && let Ok(meta) = file.metadata()
+        {
+            self.open(file, meta.ino(), 0);
+        }
+    }
+
+    fn poll(&mut self, joiner: &mut Joiner, sink: &Sink, max_size: usize) {
+        if self.file.is_none() {
+            self.reopen();
+        }
+        self.read_lines(joiner, sink, max_size);
+
+        let current = fs::metadata(&self.spec.path).ok().map(|meta| meta.ino());
+        if self.file.is_some() && current != Some(self.inode) {
+            // Rotated away or deleted. The old file has been read to the
+            // end above, including a last line without a newline.
+            let rest = std::mem::take(&mut self.partial);
+            let length = std::mem::take(&mut self.partial_len);
+            let start = self.offset - length as u64;
+            self.store_line(&rest, length, start, joiner, sink);
+            self.finish(joiner, sink);
+            self.reopen();
+            self.read_lines(joiner, sink, max_size);
+        }
+    }
+
+    fn read_lines(&mut self, joiner: &mut Joiner, sink: &Sink, max_size: usize) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        // Truncated in place, e.g. by copytruncate or `> file`
+        if file.metadata().is_ok_and(|meta| meta.len() < self.offset) {
+            if let Err(e) = file.seek(SeekFrom::Start(0)) {
+                eprintln!("Could not seek in {}: {}", self.spec.path, e);
+                return;
+            }
+            self.offset = 0;
+            self.partial.clear();
+            self.partial_len = 0;
+            self.finish(joiner, sink);
+        }
+
+        let mut chunk = vec![0u8; READ_CHUNK];
+        loop {
+            let Some(file) = &mut self.file else {
+                return;
+            };
+            let size = match file.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(size) => size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Read error on {}: {}", self.spec.path, e);
+                    return;
+                }
+            };
+            let mut line_start = self.offset - self.partial_len as u64;
+            self.offset += size as u64;
+            for piece in chunk[..size].split_inclusive(|b| *b == b'\n') {
+                let (body, complete) = match piece.split_last() {
+                    Some((b'\n', body)) => (body, true),
+                    _ => (piece, false),
+                };
+                let room = max_size.saturating_sub(self.partial.len());
+                self.partial
+                    .extend_from_slice(&body[..body.len().min(room)]);
+                self.partial_len += body.len();
+                if complete {
+                    let line = std::mem::take(&mut self.partial);
+                    let length = std::mem::take(&mut self.partial_len);
+                    self.store_line(&line, length, line_start, joiner, sink);
+                    line_start += length as u64 + 1;
+                }
+            }
+        }
+    }
+
+    /// Store a line of `length` bytes, of which `line` is the part kept,
+    /// that starts at `start` in the file.
+    fn store_line(
+        &mut self,
+        line: &[u8],
+        length: usize,
+        start: u64,
+        joiner: &mut Joiner,
+        sink: &Sink,
+    ) {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end();
+        if text.is_empty() {
+            return;
+        }
+
+        let mut entry = self.entry(text);
+        if length > line.len() {
+            entry.original_length = Some(length as i64);
+        }
+        for entry in joiner.push(entry) {
+            sink.store(&entry);
+        }
+        match joiner
+            .pending(&self.entry(""))
+            .map(|entry| entry.line_count)
+        {
+            // The line started a new entry
+            Some(Some(1)) => self.pending_start = Some(start),
+            Some(_) => {}
+            None => self.pending_start = None,
+        }
+    }
+
+    /// Store the entry pending in the joiner, for a file that was rotated
+    /// or truncated.
+    fn finish(&mut self, joiner: &mut Joiner, sink: &Sink) {
+        if let Some(entry) = joiner.take(&self.entry("")) {
+            sink.store(&entry);
+        }
+        self.pending_start = None;
+    }
+
+    fn entry(&self, message: &str) -> Entry {
+        let mut entry = Entry::new(&self.spec.source, &self.spec.severity, message);
+        entry.origin = Some(self.spec.path.clone());
+        entry
+    }
+
+    /// Record the position after the last complete line, or before the
+    /// lines still pending in the joiner. Only queued when it moved, so
+    /// the database is not touched while the file is idle.
+    fn save(&mut self, sink: &Sink, joiner: &Joiner) {
+        if self.file.is_none() {
+            return;
+        }
+        if joiner.pending(&self.entry("")).is_none() {
+            // Completed by the timeout and stored since
+            self.pending_start = None;
+        }
+        let offset = match self.pending_start {
+            Some(start) => start,
+            None => self.offset - self.partial_len as u64,
+        };
+        if self.saved == Some((self.inode, offset)) {
+            return;
+        }
+        sink.save_position(Position::File {
+            path: self.spec.path.clone(),
+            inode: self.inode,
+            offset,
+        });
+        self.saved = Some((self.inode, offset));
+    }
+}
+
+fn load_state(conn: &Connection, path: &str) -> Result<Option<(u64, u64)>> {
+    conn.query_row(
+        "SELECT inode, offset FROM file_state WHERE path = ?1",
+        params![path],
+        |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+    )
+    .optional()
+}
+
+pub fn save_state(conn: &Connection, path: &str, (inode, offset): (u64, u64)) -> Result<()> {
+    conn.execute(
+        "INSERT INTO file_state (path, inode, offset) VALUES (?1, ?2, ?3)
+         ON CONFLICT(path) DO UPDATE SET inode = excluded.inode, offset = excluded.offset",
+        params![path, inode as i64, offset as i64],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Wall-clock synchronization.
+//!
+//! StyxOS starts `ntpd` only 15 seconds after boot, so early entries carry
+//! an RTC or 1970 wall time. Until the clock is known to be right, entries
+//! are flagged `unsynced`. The clock counts as synchronized once the kernel
+//! says so (adjtimex) or once it is stepped, which a timerfd armed with
+//! `TFD_TIMER_CANCEL_ON_SET` reports. An NTP daemon that only slews the
+//! clock never steps it, so until then adjtimex is asked again every
+//! `SYNC_CHECK_INTERVAL`. On the first step the wall times of
+//! the boot's unsynced entries are re-derived from their `boottime_usec`;
+//! the original receive time is kept in `original_received_usec`.
+
+use crate::boot;
+use crate::clock;
+use crate::db;
+use crate::entry::Entry;
+use crate::sink::Sink;
+use rusqlite::{Connection, Result, params};
+use std::io;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+/// How often the kernel is asked whether the clock is synchronized yet
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn spawn(sink: Sink) -> Result<()> {
+    let conn = db::init_db()?;
+    let synced = clock::kernel_synced();
+    if synced {
+        mark_synced(&conn, None)?;
+    }
+
+    let fd = unsafe { libc::timerfd_create(libc::CLOCK_REALTIME, libc::TFD_CLOEXEC) };
+    if fd < 0 {
+        eprintln!(
+            "Could not create timerfd, clock steps go unnoticed: {}",
+            io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+    thread::spawn(move || watch(fd, conn, sink, synced));
+    Ok(())
+}
+
+fn watch(fd: libc::c_int, conn: Connection, sink: Sink, mut synced: bool) {
+    let mut armed = false;
+    loop {
+        let before = clock::realtime_usec() - clock::boottime_usec();
+        if !armed && let Err(e) = arm(fd) {
+            eprintln!("Could not watch the system clock: {}", e);
+            return;
+        }
+        armed = true;
+        let timeout = (!synced).then_some(SYNC_CHECK_INTERVAL);
+        match wait_for_step(fd, timeout) {
+            Ok(true) => armed = false,
+            Ok(false) => {
+                if clock::kernel_synced() {
+                    synced = true;
+                    if let Err(e) = mark_synced(&conn, None) {
+                        eprintln!("Could not flag the clock as synchronized: {}", e);
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Could not watch the system clock: {}", e);
+                return;
+            }
+        }
+        synced = true;
+        // Wall time of the boot as the new clock sees it
+        let started = clock::realtime_usec() - clock::boottime_usec();
+        let step = (started - before) as f64 / 1e6;
+
+        // Entries still waiting in the writer were stamped by the old clock
+        sink.flush();
+        let msg = match correct_unsynced(&conn, started) {
+            Ok(Some(corrected)) => format!(
+                "System clock stepped by {:+.3} s, corrected {} earlier entries",
+                step, corrected
+            ),
+            Ok(None) => format!("System clock stepped by {:+.3} s", step),
+            Err(e) => {
+                eprintln!("Could not correct entry times: {}", e);
+                format!("System clock stepped by {:+.3} s", step)
+            }
+        };
+        sink.store(&Entry::new("stylo", "NOTICE", &msg));
+    }
+}
+
+/// Arm the timer for the end of time, so it only ever fires because a
+/// change of the clock cancelled it.
+fn arm(fd: libc::c_int) -> io::Result<()> {
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        },
+        it_value: libc::timespec {
+            tv_sec: libc::time_t::MAX,
+            tv_nsec: 0,
+        },
+    };
+    let flags = libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET;
+    if unsafe { libc::timerfd_settime(fd, flags, &spec, ptr::null_mut()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Block until the wall clock is set, which disarms the timer, or until
+/// `timeout` passed. Returns whether the clock was set.
+fn wait_for_step(fd: libc::c_int, timeout: Option<Duration>) -> io::Result<bool> {
+    let timeout = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        let rc = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if rc == 0 {
+            return Ok(false);
+        }
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(e);
+        }
+
+        let mut expirations = 0u64;
+        let rc = unsafe {
+            libc::read(
+                fd,
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ECANCELED) => return Ok(true),
+                Some(libc::EINTR) => continue,
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+/// Re-derive the wall times of the current boot's unsynced entries from
+/// the boot's corrected start time. Only the first step after boot does
+/// this; returns `None` if the clock was synchronized before.
+fn correct_unsynced(conn: &Connection, started: i64) -> Result<Option<usize>> {
+    let boot_id = &boot::current().id;
+    let tx = conn.unchecked_transaction()?;
+    let synced: bool = tx.query_row(
+        "SELECT clock_synced_usec IS NOT NULL FROM boots WHERE boot_id = ?1",
+        params![boot_id],
+        |row| row.get(0),
+    )?;
+    if synced {
+        return Ok(None);
+    }
+
+    // Producer-supplied event times are left alone, only times stylo took
+    // from its own clock are replaced.
+    let corrected = tx.execute(
+        "UPDATE logs SET
+            original_received_usec = COALESCE(original_received_usec, received_usec),
+            received_usec = ?2 + boottime_usec,
+            time_usec = CASE WHEN time_usec = received_usec
+                             THEN ?2 + boottime_usec ELSE time_usec END,
+            timestamp = CASE WHEN time_usec = received_usec
+                             THEN datetime((?2 + boottime_usec) / 1000000, 'unixepoch')
+                             ELSE timestamp END
+         WHERE boot = (SELECT id FROM boots WHERE boot_id = ?1)
+           AND unsynced AND boottime_usec IS NOT NULL",
+        params![boot_id, started],
+    )?;
+    mark_synced(&tx, Some(started))?;
+    tx.commit()?;
+    Ok(Some(corrected))
+}
+
+/// Flag the current boot's clock as synchronized, optionally with the
+/// boot's start time derived from the correct clock.
+fn mark_synced(conn: &Connection, started: Option<i64>) -> Result<()> {
+    conn.execute(
+        "UPDATE boots SET clock_synced_usec = ?2, started_usec = COALESCE(?3, started_usec)
+         WHERE boot_id = ?1 AND clock_synced_usec IS NULL",
+        params![boot::current().id, clock::realtime_usec(), started],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,293 @@
+//! Syslog over TLS (RFC 5425).
+//!
+//! The listener accepts octet-counted frames from TLS clients and stores
+//! the subject of a verified client certificate with every entry. The
+//! forwarder relays every ingested entry to a remote collector, optionally
+//! authenticating with a client certificate of its own.
+//!
+//! Like the TCP listener, the TLS listener limits the connections it
+//! serves at once and closes idle ones (see net.rs). A client gets
+//! `HANDSHAKE_TIMEOUT` to complete the handshake, however slowly it
+//! trickles the bytes in.
+
+use crate::config::{ClientAuth, Config};
+use crate::entry::Entry;
+use crate::net;
+use crate::sink::Sink;
+use crate::syslog;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Entries buffered for the collector before new ones are dropped.
+const FORWARD_QUEUE: usize = 4096;
+/// Minimum pause between connection attempts to an unreachable collector.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Time a client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the forwarder waits for the collector to accept a connection or
+/// take a frame
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn spawn_listener(addr: SocketAddr, cfg: &Config, sink: Sink) {
+    let tls = server_config(cfg).unwrap_or_else(|e| panic!("Could not set up TLS listener: {}", e));
+    let listener = TcpListener::bind(addr)
+        .unwrap_or_else(|e| panic!("Could not bind TLS listener {}: {}", addr, e));
+    println!("Stylo daemon listening on tls://{}", addr);
+    let max_size = cfg.max_message_size();
+
+    thread::spawn(move || {
+        let connections = net::Connections::default();
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TLS accept error: {}", e);
+                    continue;
+                }
+            };
+            let Ok(peer) = stream.peer_addr() else {
+                continue;
+            };
+            let Some(admitted) = connections.admit(peer) else {
+                continue;
+            };
+            let tls = tls.clone();
+            let conn_sink = sink.clone();
+            thread::spawn(move || {
+                serve_tls(stream, peer, tls, max_size, &conn_sink);
+                drop(admitted);
+            });
+        }
+    });
+}
+
+fn serve_tls(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tls: Arc<ServerConfig>,
+    max_size: usize,
+    sink: &Sink,
+) {
+    let mut conn = match ServerConnection::new(tls) {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("TLS setup failed for {}: {}", peer, e);
+            return;
+        }
+    };
+
+    // Finish the handshake first so the client certificate is known
+    // before the first frame is stored.
+    let mut handshake = Deadline {
+        stream: &mut stream,
+        deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+    };
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut handshake) {
+            eprintln!("TLS handshake with {} failed: {}", peer, e);
+            return;
+        }
+    }
+    let timeouts = stream
+        .set_read_timeout(Some(net::IDLE_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)));
+    if let Err(e) = timeouts {
+        eprintln!("Could not set timeout for {}: {}", peer, e);
+        return;
+    }
+
+    let subject = conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| x509_parser::parse_x509_certificate(cert).ok())
+        .map(|(_, cert)| cert.subject().to_string());
+
+    net::serve_frames(
+        StreamOwned::new(conn, stream),
+        peer,
+        subject.as_deref(),
+        max_size,
+        sink,
+    );
+}
+
+/// A stream whose reads and writes fail once `deadline` has passed, no
+/// matter how many of them there are.
+struct Deadline<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline<'_> {
+    /// Limit the next read or write to the time that is left.
+    fn limit(&self) -> io::Result<()> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.set_write_timeout(Some(left))
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.limit()?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.limit()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn server_config(cfg: &Config) -> Result<Arc<ServerConfig>, String> {
+    let cert = cfg.tls_cert.as_deref().ok_or("tls_cert is not set")?;
+    let key = cfg.tls_key.as_deref().ok_or("tls_key is not set")?;
+
+    let builder = ServerConfig::builder();
+    let builder = match cfg.tls_client_auth {
+        ClientAuth::None => builder.with_no_client_auth(),
+        auth => {
+            let ca = cfg
+                .tls_client_ca
+                .as_deref()
+                .ok_or("tls_client_ca is not set")?;
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?));
+            if auth == ClientAuth::Optional {
+                verifier = verifier.allow_unauthenticated();
+            }
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| e.to_string())?;
+    Ok(Arc::new(config))
+}
+
+/// Start the forwarder thread and return the queue feeding it. Entries
+/// are dropped while the collector is unreachable.
+pub fn spawn_forwarder(target: &str, cfg: &Config) -> SyncSender<Entry> {
+    let tls =
+        client_config(cfg).unwrap_or_else(|e| panic!("Could not set up TLS forwarder: {}", e));
+    let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+    let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+        .unwrap_or_else(|e| panic!("Invalid forward target {}: {}", target, e));
+    let hostname = local_hostname();
+    let addr = target.to_string();
+
+    let (tx, rx) = mpsc::sync_channel::<Entry>(FORWARD_QUEUE);
+    thread::spawn(move || {
+        let mut stream = None;
+        let mut last_attempt: Option<Instant> = None;
+
+        for entry in rx {
+            let frame = syslog::format_rfc5424(&entry, &hostname);
+            let frame = format!("{} {}", frame.len(), frame);
+
+            // Retry once on a fresh connection if the old one went away
+            for _ in 0..2 {
+                if stream.is_none() && last_attempt.is_none_or(|t| t.elapsed() >= RECONNECT_DELAY) {
+                    last_attempt = Some(Instant::now());
+                    stream = connect(&addr, name.clone(), tls.clone())
+                        .map_err(|e| eprintln!("Could not connect to {}: {}", addr, e))
+                        .ok();
+                }
+                let Some(s) = stream.as_mut() else {
+                    break;
+                };
+                if s.write_all(frame.as_bytes())
+                    .and_then(|_| s.flush())
+                    .is_ok()
+                {
+                    break;
+                }
+                stream = None;
+            }
+        }
+    });
+    println!("Stylo daemon forwarding to tls://{}", target);
+    tx
+}
+
+fn connect(
+    target: &str,
+    name: ServerName<'static>,
+    tls: Arc<ClientConfig>,
+) -> Result<StreamOwned<ClientConnection, TcpStream>, String> {
+    let conn = ClientConnection::new(tls, name).map_err(|e| e.to_string())?;
+    let mut last_error = format!("{} does not resolve", target);
+    for addr in target.to_socket_addrs().map_err(|e| e.to_string())? {
+        match TcpStream::connect_timeout(&addr, FORWARD_TIMEOUT) {
+            Ok(sock) => {
+                // The handshake happens with the first write
+                sock.set_read_timeout(Some(FORWARD_TIMEOUT))
+                    .and_then(|_| sock.set_write_timeout(Some(FORWARD_TIMEOUT)))
+                    .map_err(|e| e.to_string())?;
+                return Ok(StreamOwned::new(conn, sock));
+            }
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(last_error)
+}
+
+fn client_config(cfg: &Config) -> Result<Arc<ClientConfig>, String> {
+    let ca = cfg
+        .tls_forward_ca
+        .as_deref()
+        .ok_or("tls_forward_ca is not set")?;
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+
+    let config = match (&cfg.tls_forward_cert, &cfg.tls_forward_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| e.to_string())?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+fn local_hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "-".to_string())
+}
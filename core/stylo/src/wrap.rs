@@ -0,0 +1,253 @@
+//! Supervised programs: `stylo wrap` and `stylo container-log`.
+//!
+//! Every line the child writes to stdout or stderr becomes an entry; the
+//! exit status is logged last and passed on as stylo's own exit code, so
+//! init and service scripts can wrap daemons transparently. SIGTERM,
+//! SIGINT, SIGHUP and SIGQUIT are passed on to the child; stylo keeps
+//! logging until it exits. Lines longer than `max_message_size` are cut
+//! off like on every other input.
+//!
+//! `container-log` does the same for a crun container run in the
+//! foreground: crun hands the container's stdio through, so every line is
+//! tagged with the container id, and start and exit are logged as
+//! lifecycle events with `event` and `exit_code` fields.
+
+use crate::config::Config;
+use crate::entry::Entry;
+use crate::multiline::Joiner;
+use crate::net;
+use crate::sink::Sink;
+use rusqlite::Result;
+use serde_json::Value;
+use std::io::{BufReader, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+
+/// Signals meant for the supervised program rather than stylo
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGQUIT];
+
+struct Options {
+    source: Option<String>,
+    stdout_severity: String,
+    stderr_severity: String,
+    container: Option<String>,
+}
+
+/// `stylo wrap [OPTIONS] -- COMMAND [ARGS...]`
+pub fn run(args: &[String]) -> Result<()> {
+    let (opts, command) = parse_options(args, None);
+    supervise(opts, &command)
+}
+
+/// `stylo container-log ID [OPTIONS] -- COMMAND [ARGS...]`
+pub fn run_container(args: &[String]) -> Result<()> {
+    let Some((id, rest)) = args.split_first() else {
+        crate::print_usage();
+        process::exit(1);
+    };
+    let (opts, command) = parse_options(rest, Some(id.clone()));
+    supervise(opts, &command)
+}
+
+fn parse_options(args: &[String], container: Option<String>) -> (Options, Vec<String>) {
+    let mut opts = Options {
+        source: container.clone(),
+        stdout_severity: "INFO".to_string(),
+        stderr_severity: "ERROR".to_string(),
+        container,
+    };
+
+    let mut iter = args.iter();
+    let command: Vec<String> = loop {
+        match iter.next().map(String::as_str) {
+            Some("-s" | "--source") => opts.source = iter.next().cloned(),
+            Some("--stdout") => {
+                opts.stdout_severity = iter.next().cloned().unwrap_or(opts.stdout_severity)
+            }
+            Some("--stderr") => {
+                opts.stderr_severity = iter.next().cloned().unwrap_or(opts.stderr_severity)
+            }
+            Some("--") => break iter.cloned().collect(),
+            _ => {
+                crate::print_usage();
+                process::exit(1);
+            }
+        }
+    };
+    if command.is_empty() {
+        crate::print_usage();
+        process::exit(1);
+    }
+    (opts, command)
+}
+
+fn supervise(opts: Options, command: &[String]) -> Result<()> {
+    let (program, program_args) = command
+        .split_first()
+        .expect("command checked by parse_options");
+    let source = opts.source.clone().unwrap_or_else(|| {
+        Path::new(program)
+            .file_name()
+            .map_or(program.to_string(), |name| {
+                name.to_string_lossy().to_string()
+            })
+    });
+    // Lifecycle entries name the container if there is one
+    let subject = match &opts.container {
+        Some(id) => format!("container {}", id),
+        None => program.to_string(),
+    };
+    let new_entry = |severity: &str, message: &str, pid: Option<i32>| {
+        let mut entry = Entry::new(&source, severity, message);
+        entry.pid = pid;
+        entry.container = opts.container.clone();
+        entry
+    };
+
+    // Before the writer thread starts, so that it inherits the mask
+    let signals = crate::block_signals(&FORWARDED_SIGNALS);
+    let cfg = Config::load();
+    let sink = Sink::open(&cfg, None)?;
+    let mut command = Command::new(program);
+    unsafe {
+        // The child takes the signals itself
+        command.pre_exec(move || {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &signals, std::ptr::null_mut());
+            Ok(())
+        });
+    }
+    let mut child = match command
+        .args(program_args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let msg = format!("Could not start {}: {}", subject, e);
+            sink.store(&new_entry("ERROR", &msg, None));
+            sink.flush();
+            eprintln!("{}", msg);
+            process::exit(127);
+        }
+    };
+    let pid = Some(child.id() as i32);
+    forward_signals(signals, child.id() as libc::pid_t);
+
+    if opts.container.is_some() {
+        let mut entry = new_entry("INFO", &format!("{} started", subject), pid);
+        entry
+            .fields
+            .push(("event".to_string(), Value::from("start")));
+        sink.store(&entry);
+    }
+
+    // One reader per stream; the lines are written here, on a single
+    // connection, in the order they arrive.
+    let (tx, rx) = mpsc::channel();
+    let max_size = cfg.max_message_size();
+    if let Some(stdout) = child.stdout.take() {
+        spawn_reader(stdout, "stdout", max_size, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn_reader(stderr, "stderr", max_size, tx.clone());
+    }
+    drop(tx);
+
+    // Backtraces are joined by the source's multiline rule, if any
+    let mut joiner = Joiner::new(&cfg);
+    loop {
+        let (stream, line, length) = match rx.recv_timeout(joiner.timeout()) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                for entry in joiner.expired() {
+                    sink.store(&entry);
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let severity = match stream {
+            "stdout" => &opts.stdout_severity,
+            _ => &opts.stderr_severity,
+        };
+        let mut entry = new_entry(severity, &line, pid);
+        entry.stream = Some(stream.to_string());
+        entry.original_length = length.map(|length| length as i64);
+        for entry in joiner.push(entry).into_iter().chain(joiner.expired()) {
+            sink.store(&entry);
+        }
+    }
+    for entry in joiner.flush() {
+        sink.store(&entry);
+    }
+
+    let status = match child.wait() {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Could not wait for {}: {}", subject, e);
+            sink.flush();
+            process::exit(1);
+        }
+    };
+    let (code, msg) = match (status.code(), status.signal()) {
+        (Some(code), _) => (code, format!("{} exited with status {}", subject, code)),
+        (None, Some(signal)) => (
+            128 + signal,
+            format!("{} killed by signal {}", subject, signal),
+        ),
+        (None, None) => (1, format!("{} terminated", subject)),
+    };
+    let mut entry = new_entry(if code == 0 { "INFO" } else { "ERROR" }, &msg, pid);
+    if opts.container.is_some() {
+        entry
+            .fields
+            .push(("event".to_string(), Value::from("exit")));
+        entry
+            .fields
+            .push(("exit_code".to_string(), Value::from(code)));
+    }
+    sink.store(&entry);
+
+    // process::exit skips destructors, the writer has to finish first
+    sink.flush();
+    process::exit(code);
+}
+
+/// Pass the blocked `signals` on to the child for as long as stylo runs.
+fn forward_signals(signals: libc::sigset_t, child: libc::pid_t) {
+    thread::spawn(move || {
+        loop {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&signals, &mut signal) } == 0 {
+                unsafe { libc::kill(child, signal) };
+            }
+        }
+    });
+}
+
+/// Send the lines of `stream`, cut off after `max_size` bytes, along with
+/// the full length of those that were.
+fn spawn_reader<R: Read + Send + 'static>(
+    stream: R,
+    name: &'static str,
+    max_size: usize,
+    tx: Sender<(&'static str, String, Option<usize>)>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        while let Ok(Some(length)) = net::read_line(&mut reader, max_size, &mut line) {
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            let cut = (length > line.len()).then_some(length);
+            if tx.send((name, text, cut)).is_err() {
+                return;
+            }
+        }
+    });
+}
@@ -4,7 +4,24 @@ setup() {
     # Use local files for testing
     export STYLO_DB="test_log.db"
     export STYLO_SOCK="test_log.sock"
-    rm -f "$STYLO_DB" "$STYLO_DB-wal" "$STYLO_DB-shm" "$STYLO_SOCK"
+    export STYLO_SYSLOG_SOCK="test_syslog.sock"
+    export STYLO_CONF="test_stylo.conf"
+    export STYLO_KMSG="test_kmsg"
+    rm -f "$STYLO_DB" "$STYLO_DB-wal" "$STYLO_DB-shm" "$STYLO_SOCK" "$STYLO_SYSLOG_SOCK" "$STYLO_CONF" "$STYLO_KMSG"
+}
+
+# Self-signed CA plus server and client certificates for the TLS tests
+make_certs() {
+    mkdir -p test_tls
+    openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 1 \
+        -subj "/CN=Test CA" -keyout test_tls/ca.key -out test_tls/ca.crt 2>/dev/null
+    for name in server client; do
+        openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -subj "/O=StyxOS/CN=$name" \
+            -keyout test_tls/$name.key -out test_tls/$name.csr 2>/dev/null
+        printf 'subjectAltName=IP:127.0.0.1\nbasicConstraints=CA:FALSE\n' > test_tls/$name.ext
+        openssl x509 -req -in test_tls/$name.csr -CA test_tls/ca.crt -CAkey test_tls/ca.key \
+            -CAcreateserial -days 1 -extfile test_tls/$name.ext -out test_tls/$name.crt 2>/dev/null
+    done
 }
 
 teardown() {
@@ -43,6 +60,933 @@ teardown() {
     [ "$result" == "NOTICE" ]
 }
 
+@test "daemon: parsing RFC 3164 frames on the syslog socket" {
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    printf '<30>Oct 18 10:00:00 node01 udhcpc[42]: lease obtained' | socat - UNIX-SENDTO:"$STYLO_SYSLOG_SOCK"
+    printf '<13>Oct  8 09:15:02 root: no hostname here' | socat - UNIX-SENDTO:"$STYLO_SYSLOG_SOCK"
+    sleep 0.2
+
+    first=$(sqlite3 "$STYLO_DB" "SELECT source, severity, facility, hostname, procid, message FROM logs WHERE app_name='udhcpc';")
+    second=$(sqlite3 "$STYLO_DB" "SELECT source, severity, facility, hostname IS NULL, message FROM logs WHERE app_name='root';")
+
+    kill $DAEMON_PID
+    [ "$first" == "udhcpc|INFO|daemon|node01|42|lease obtained" ]
+    [ "$second" == "root|NOTICE|user|1|no hostname here" ]
+}
+
+@test "daemon: parsing RFC 5424 frames with structured data" {
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    printf '<165>1 2026-10-18T10:00:00.123Z node02 crun 811 ID47 [exampleSDID@32473 iut="3" eventSource="App\]"] container started' \
+        | socat - UNIX-SENDTO:"$STYLO_SYSLOG_SOCK"
+    sleep 0.2
+
+    result=$(sqlite3 "$STYLO_DB" "SELECT source, severity, facility, hostname, procid, msgid, structured_data, message FROM logs;")
+
+    kill $DAEMON_PID
+    [ "$result" == 'crun|NOTICE|local4|node02|811|ID47|[exampleSDID@32473 iut="3" eventSource="App\]"]|container started' ]
+}
+
+@test "daemon: receiving syslog over UDP and TCP" {
+    printf 'udp_listen = 127.0.0.1:15514\ntcp_listen = 127.0.0.1:15514\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    printf '<11>Oct 18 10:00:00 switch01 stp: port 3 blocking' | socat - UDP-SENDTO:127.0.0.1:15514
+    # One octet-counted and one newline-terminated frame on the same connection
+    printf '25 <14>router01 bgp: peer up<12>router01 bgp: peer flapping\n' | socat - TCP:127.0.0.1:15514
+    sleep 0.2
+
+    udp=$(sqlite3 "$STYLO_DB" "SELECT hostname, severity, origin LIKE '127.0.0.1:%' FROM logs WHERE source='stp';")
+    tcp=$(sqlite3 "$STYLO_DB" "SELECT severity || ':' || message FROM logs WHERE source='bgp' ORDER BY id;" | tr '\n' ' ')
+
+    kill $DAEMON_PID
+    [ "$udp" == "switch01|ERROR|1" ]
+    [ "$tcp" == "INFO:peer up WARNING:peer flapping " ]
+}
+
+@test "daemon: bounding oversized TCP frames" {
+    printf 'tcp_listen = 127.0.0.1:15515\nmax_message_size = 100\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    # A long newline-terminated frame does not cost its length in memory
+    { printf '<14>host big: '; head -c 2000000 /dev/zero | tr '\0' x; printf '\n<14>host next: after\n'; } \
+        | socat -u - TCP:127.0.0.1:15515
+    # An octet count without end closes the connection
+    printf '1234567890123456789' | socat -u - TCP:127.0.0.1:15515
+    sleep 0.3
+    kill $DAEMON_PID
+
+    big=$(sqlite3 "$STYLO_DB" "SELECT original_length FROM logs WHERE source='big';")
+    next=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='next';")
+    [ "$big" == "2000014" ]
+    [ "$next" == "after" ]
+}
+
+@test "daemon: limiting the TCP connections of a single address" {
+    echo 'tcp_listen = 127.0.0.1:15516' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    # Sixteen idle connections are served, the seventeenth is closed
+    refused=$(python3 -c '
+import socket
+held = [socket.create_connection(("127.0.0.1", 15516)) for _ in range(16)]
+extra = socket.create_connection(("127.0.0.1", 15516))
+extra.settimeout(2)
+print(extra.recv(1) == b"")
+held[0].sendall(b"<14>host held: still served\n")
+')
+    sleep 0.3
+    kill $DAEMON_PID
+
+    served=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='held';")
+    [ "$refused" == "True" ]
+    [ "$served" == "still served" ]
+}
+
+@test "daemon: receiving and forwarding syslog over mutual TLS" {
+    make_certs
+    cat > "$STYLO_CONF" <<EOF
+tls_listen = 127.0.0.1:16514
+tls_cert = test_tls/server.crt
+tls_key = test_tls/server.key
+tls_client_auth = required
+tls_client_ca = test_tls/ca.crt
+EOF
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    # A second daemon on its own sockets forwards everything to the first
+    cat > test_forward.conf <<EOF
+tls_forward = 127.0.0.1:16514
+tls_forward_ca = test_tls/ca.crt
+tls_forward_cert = test_tls/client.crt
+tls_forward_key = test_tls/client.key
+EOF
+    STYLO_CONF=test_forward.conf STYLO_DB=test_forward.db STYLO_SOCK=test_forward.sock \
+        STYLO_SYSLOG_SOCK=test_forward_syslog.sock ./target/debug/stylo -d &
+    FORWARD_PID=$!
+    sleep 0.2
+
+    printf '31 <13>1 - node03 app - - - direct' \
+        | socat - OPENSSL:127.0.0.1:16514,cafile=test_tls/ca.crt,cert=test_tls/client.crt,key=test_tls/client.key
+    echo "pluto WARNING disk almost full" | socat - UNIX-SENDTO:test_forward.sock
+    echo '{"source":"disk monitor","message":"stamped","timestamp":1760781600.25}' | socat - UNIX-SENDTO:test_forward.sock
+    sleep 0.5
+
+    direct=$(sqlite3 "$STYLO_DB" "SELECT hostname, message, peer_subject FROM logs WHERE source='app';")
+    forwarded=$(sqlite3 "$STYLO_DB" "SELECT severity, message, peer_subject, time_usec < received_usec FROM logs WHERE source='pluto';")
+    stamped=$(sqlite3 "$STYLO_DB" "SELECT source, time_usec, timestamp FROM logs WHERE message='stamped';")
+
+    kill $DAEMON_PID $FORWARD_PID
+    rm -rf test_tls test_forward*
+    [ "$direct" == "node03|direct|O=StyxOS, CN=client" ]
+    [ "$forwarded" == "WARNING|disk almost full|O=StyxOS, CN=client|1" ]
+    [ "$stamped" == "disk_monitor|1760781600250000|2025-10-18 10:00:00" ]
+}
+
+@test "daemon: storing extra fields of JSON datagrams" {
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    echo '{"source":"charon","severity":"ERROR","message":"upstream timeout","timestamp":1760781600,"upstream":"9.9.9.9","timeout_ms":3000,"retry":true}' \
+        | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    echo "network NOTICE link_up" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+
+    entry=$(sqlite3 "$STYLO_DB" "SELECT source, severity, message, timestamp FROM logs WHERE source='charon';")
+    slow=$(sqlite3 "$STYLO_DB" "SELECT l.message FROM logs l JOIN log_fields f ON f.log_id = l.id WHERE f.key='timeout_ms' AND f.value > 1000;")
+    fields=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM log_fields;")
+    legacy=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='network';")
+
+    kill $DAEMON_PID
+    [ "$entry" == "charon|ERROR|upstream timeout|2025-10-18 10:00:00" ]
+    [ "$slow" == "upstream timeout" ]
+    [ "$fields" -eq 3 ]
+    [ "$legacy" == "link_up" ]
+}
+
+@test "daemon: recording sender credentials and stamping the source" {
+    echo "source_policy = stamp" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    echo "charon ERROR forged" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+
+    forged=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='charon';")
+    # socat is not in a trusted directory here, so it is named by its path
+    sender=$(sqlite3 "$STYLO_DB" "SELECT uid, gid, pid > 0, exe = source OR exe LIKE '%/' || source FROM logs WHERE message='forged';")
+
+    kill $DAEMON_PID
+    [ "$forged" -eq 0 ]
+    [ "$sender" == "$(id -u)|$(id -g)|1|1" ]
+}
+
+@test "daemon: rejecting entries with a spoofed source" {
+    echo "source_policy = verify" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    echo "charon ERROR forged" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+
+    forged=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE message='forged';")
+    warning=$(sqlite3 "$STYLO_DB" "SELECT source, severity, uid FROM logs WHERE message LIKE 'Rejected entry from pid % claiming source ''charon''';")
+
+    kill $DAEMON_PID
+    [ "$forged" -eq 0 ]
+    [ "$warning" == "stylo|WARNING|$(id -u)" ]
+}
+
+@test "daemon: trusting the executable, not the process name, for the source" {
+    python=$(python3 -c 'import os; print(os.path.realpath("/proc/self/exe"))')
+    printf 'source_policy = verify\nsource_exe.backup = %s\n' "$python" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    python3 - "$STYLO_SOCK" <<'PY'
+import socket, sys
+sock = socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM)
+with open("/proc/self/comm", "w") as comm:
+    comm.write("charon")
+sock.sendto(b"charon ERROR spoofed via comm", sys.argv[1])
+sock.sendto(b"backup INFO allowed by source_exe", sys.argv[1])
+PY
+    # A copy named after the daemon proves nothing
+    mkdir -p test_bin
+    cp "$python" test_bin/charon
+    test_bin/charon -c 'import socket, sys; socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM).sendto(b"charon ERROR forged by a copy", sys.argv[1])' "$STYLO_SOCK"
+    rm -rf test_bin
+    sleep 0.2
+
+    spoofed=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE message = 'spoofed via comm';")
+    rejected=$(sqlite3 "$STYLO_DB" "SELECT comm FROM logs WHERE message LIKE 'Rejected entry from pid % claiming source ''charon''' ORDER BY id LIMIT 1;")
+    allowed=$(sqlite3 "$STYLO_DB" "SELECT source, exe FROM logs WHERE message = 'allowed by source_exe';")
+    copied=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE message = 'forged by a copy';")
+    copy=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source = 'stylo' AND exe LIKE '%/test_bin/charon';")
+
+    kill $DAEMON_PID
+    [ "$spoofed" -eq 0 ]
+    [ "$copied" -eq 0 ]
+    [ "$copy" -eq 1 ]
+    [ "$rejected" == "charon" ]
+    [ "$allowed" == "backup|$python" ]
+}
+
+@test "daemon: closing file descriptors passed along with datagrams" {
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    before=$(ls /proc/$DAEMON_PID/fd | wc -l)
+
+    python3 - "$STYLO_SOCK" <<'PY'
+import array, os, socket, sys
+sock = socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM)
+fds = [os.open("/dev/null", os.O_RDONLY) for _ in range(64)]
+def send(message, fds):
+    rights = [(socket.SOL_SOCKET, socket.SCM_RIGHTS, array.array("i", fds))]
+    sock.sendmsg([message], rights, 0, sys.argv[1])
+for i in range(50):
+    send(b"fds INFO with descriptors", fds[:8])
+send(b"fds INFO too many descriptors", fds)
+PY
+    sleep 0.3
+    after=$(ls /proc/$DAEMON_PID/fd | wc -l)
+    stored=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source = 'fds';")
+    rejected=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source = 'stylo' AND message = 'Rejected datagram with truncated control data';")
+
+    kill $DAEMON_PID
+    [ "$after" -eq "$before" ]
+    [ "$stored" -eq 50 ]
+    [ "$rejected" -eq 1 ]
+}
+
+@test "daemon: attributing entries to the sender's container" {
+    cgroup_root=$(awk '$3 == "cgroup2" { print $2; exit }' /proc/mounts)
+    if [ -z "$cgroup_root" ] || ! mkdir -p "$cgroup_root/stylo-test-web" 2>/dev/null; then
+        skip "needs a writable cgroup2 hierarchy"
+    fi
+
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    # Send from a process inside the container's cgroup, as crun would place it
+    (echo $BASHPID > "$cgroup_root/stylo-test-web/cgroup.procs" &&
+        echo "web INFO request served" | socat - UNIX-SENDTO:"$STYLO_SOCK")
+    echo "host INFO not in a container" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+
+    web=$(sqlite3 "$STYLO_DB" "SELECT cgroup, message FROM logs WHERE container = 'stylo-test-web';")
+    host=$(sqlite3 "$STYLO_DB" "SELECT container IS NULL FROM logs WHERE source = 'host';")
+
+    kill $DAEMON_PID
+    rmdir "$cgroup_root/stylo-test-web"
+    [ "$web" == "/stylo-test-web|request served" ]
+    [ "$host" == "1" ]
+}
+
+@test "daemon: flagging messages larger than max_message_size" {
+    echo "max_message_size = 8192" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    printf 'big INFO %s' "$(head -c 6000 /dev/zero | tr '\0' x)" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    printf 'huge INFO %s' "$(head -c 10000 /dev/zero | tr '\0' x)" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    kill $DAEMON_PID
+
+    big=$(sqlite3 "$STYLO_DB" "SELECT length(message), truncated FROM logs WHERE source='big';")
+    huge=$(sqlite3 "$STYLO_DB" "SELECT length(message), truncated, original_length FROM logs WHERE source='huge';")
+    [ "$big" == "6000|" ]
+    [ "$huge" == "8182|1|10010" ]
+}
+
+@test "daemon: joining continuation lines into one entry" {
+    printf 'multiline.app = indent\nmultiline.job = start:^\\[\nmultiline_timeout_ms = 200\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+
+    # One sender, one datagram per line
+    for line in "app ERROR panicked at main.rs:3" "app ERROR   0: main" "app ERROR   1: start" "app INFO restarting"; do
+        sleep 0.1
+        echo "$line"
+    done | socat -u - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.6
+    kill $DAEMON_PID
+
+    run ./target/debug/stylo wrap -s job -- sh -c 'echo "[1] Traceback:"; echo "File x.py"; echo "[2] done"'
+
+    joined=$(sqlite3 "$STYLO_DB" "SELECT severity, line_count, message FROM logs WHERE source='app' ORDER BY id LIMIT 1;")
+    next=$(sqlite3 "$STYLO_DB" "SELECT line_count, message FROM logs WHERE source='app' ORDER BY id DESC LIMIT 1;")
+    job=$(sqlite3 "$STYLO_DB" "SELECT line_count FROM logs WHERE source='job' AND stream='stdout' ORDER BY id;" | tr '\n' ' ')
+    [ "$joined" == "$(printf 'ERROR|3|panicked at main.rs:3\n  0: main\n  1: start')" ]
+    [ "$next" == "1|restarting" ]
+    [ "$job" == "2 1 " ]
+}
+
+@test "daemon: storing entries held for joining on shutdown" {
+    printf 'multiline.app = indent\nmultiline_timeout_ms = 60000\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+    # One sender, one datagram per line
+    { echo "app ERROR panic in worker"; sleep 0.1; echo "app ERROR   at main.rs:3"; } \
+        | socat -u - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+
+    joined=$(sqlite3 "$STYLO_DB" "SELECT line_count, replace(message, char(10), '/') FROM logs WHERE source='app';")
+    [ "$joined" == "2|panic in worker/  at main.rs:3" ]
+}
+
+@test "daemon: following a log file across rotation, truncation and restarts" {
+    rm -f test_tail.log test_tail.log.1
+    printf 'one\ntwo\n' > test_tail.log
+    echo "tail_file = test_tail.log vendor WARNING" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+
+    echo three >> test_tail.log
+    sleep 0.2
+    mv test_tail.log test_tail.log.1
+    echo four > test_tail.log
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+
+    echo five >> test_tail.log
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    echo six > test_tail.log
+    sleep 0.3
+    kill $DAEMON_PID
+    rm -f test_tail.log test_tail.log.1
+
+    lines=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='vendor' AND severity='WARNING' ORDER BY id;" | tr '\n' ' ')
+    origin=$(sqlite3 "$STYLO_DB" "SELECT DISTINCT origin FROM logs WHERE source='vendor';")
+    [ "$lines" == "one two three four five six " ]
+    [ "$origin" == "test_tail.log" ]
+}
+
+@test "daemon: keeping lines held for joining and long lines of a followed file" {
+    rm -f test_tail.log
+    printf 'tail_file = test_tail.log vendor INFO\nmultiline.vendor = indent\nmultiline_timeout_ms = 60000\nmax_message_size = 100\n' > "$STYLO_CONF"
+    echo first > test_tail.log
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    # Still waiting for continuation lines when the daemon stops
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+    offset=$(sqlite3 "$STYLO_DB" "SELECT offset FROM file_state WHERE path='test_tail.log';")
+
+    printf '  more\nsecond\n' >> test_tail.log
+    head -c 300000 /dev/zero | tr '\0' x >> test_tail.log
+    printf '\nthird\n' >> test_tail.log
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+    rm -f test_tail.log
+
+    lines=$(sqlite3 "$STYLO_DB" "SELECT line_count, replace(message, char(10), '/') FROM logs WHERE source='vendor' ORDER BY id LIMIT 2;" | tr '\n' ' ')
+    long=$(sqlite3 "$STYLO_DB" "SELECT length(message), original_length FROM logs WHERE source='vendor' AND message LIKE 'xxx%';")
+    [ "$offset" -eq 0 ]
+    [ "$lines" == "2|first/  more 1|second " ]
+    [ "$long" == "100|300000" ]
+}
+
+@test "daemon: normalizing severities to RFC 5424 levels" {
+    ./target/debug/stylo legacy Warn "kept as WARNING"
+    ./target/debug/stylo legacy odd "kept as given"
+
+    echo "unknown_severity = reject" > "$STYLO_CONF"
+    run ./target/debug/stylo legacy odd "rejected"
+    [ "$status" -eq 1 ]
+
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+    echo "app err disk failing" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    echo "app LOUD hello" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    kill $DAEMON_PID
+
+    legacy=$(sqlite3 "$STYLO_DB" "SELECT severity, level FROM logs WHERE source='legacy' ORDER BY id;" | tr '\n' ' ')
+    app=$(sqlite3 "$STYLO_DB" "SELECT severity, level, message FROM logs WHERE source='app';")
+    rejected=$(sqlite3 "$STYLO_DB" "SELECT severity, message FROM logs WHERE source='stylo';")
+    [ "$legacy" == "WARNING|4 odd| " ]
+    [ "$app" == "ERROR|3|disk failing" ]
+    [ "$rejected" == "WARNING|Rejected entry from 'app' with unknown severity 'LOUD'" ]
+}
+
+@test "daemon: storing microsecond event and receive times" {
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+    echo "<14>1 2026-10-18T10:00:00.123456+02:00 host app - - - stamped" | socat - UNIX-SENDTO:"$STYLO_SYSLOG_SOCK"
+    echo '{"source":"json","message":"float","timestamp":1792310400.5}' | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    echo "plain INFO unstamped" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    kill $DAEMON_PID
+
+    stamped=$(sqlite3 "$STYLO_DB" "SELECT timestamp, time_usec, received_usec != time_usec FROM logs WHERE source='app';")
+    json=$(sqlite3 "$STYLO_DB" "SELECT time_usec FROM logs WHERE source='json';")
+    plain=$(sqlite3 "$STYLO_DB" "SELECT time_usec = received_usec, boottime_usec >= monotonic_usec FROM logs WHERE source='plain';")
+    [ "$stamped" == "2026-10-18 08:00:00|1792310400123456|1" ]
+    [ "$json" == "1792310400500000" ]
+    [ "$plain" == "1|1" ]
+}
+
+@test "daemon: writing entries in batches and committing them on shutdown" {
+    printf 'batch_size = 3\nbatch_latency_ms = 60000\ndurability = normal\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+
+    for i in 1 2 3 4 5; do
+        echo "burst INFO entry $i" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    done
+    sleep 0.3
+    # The first batch is full, the second waits for more entries
+    committed=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='burst';")
+
+    kill $DAEMON_PID
+    wait $DAEMON_PID
+    stored=$(sqlite3 "$STYLO_DB" "SELECT group_concat(message, ',') FROM logs WHERE source='burst' ORDER BY id;")
+
+    [ "$committed" -eq 3 ]
+    [ "$stored" == "entry 1,entry 2,entry 3,entry 4,entry 5" ]
+}
+
+@test "daemon: dropping the least severe entries when the write queue is full" {
+    printf 'queue_size = 2\nbatch_size = 1\noverflow = drop_severity\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+
+    # Hold the write lock so that the writer falls behind
+    (echo "BEGIN IMMEDIATE;"; sleep 1.5; echo "COMMIT;") | sqlite3 "$STYLO_DB" &
+    LOCK_PID=$!
+    sleep 0.3
+    for line in "queue INFO first" "queue DEBUG a" "queue INFO b" "queue ERROR c" "queue WARNING d"; do
+        echo "$line" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+        sleep 0.05
+    done
+    wait $LOCK_PID
+
+    kill $DAEMON_PID
+    wait $DAEMON_PID
+    stored=$(sqlite3 "$STYLO_DB" "SELECT group_concat(message, ',') FROM logs WHERE source='queue' ORDER BY id;")
+    report=$(sqlite3 "$STYLO_DB" "SELECT severity, message, value FROM logs JOIN log_fields ON log_id = logs.id WHERE source='stylo' AND key='dropped';")
+
+    [ "$stored" == "first,c,d" ]
+    [ "$report" == "WARNING|Dropped 2 entries, write queue full (1 INFO, 1 DEBUG)|2" ]
+}
+
+@test "daemon: counting datagrams the kernel dropped on a full receive buffer" {
+    printf 'udp_listen = 127.0.0.1:15515\nreceive_buffer = 4096\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+
+    # Nothing is read while the daemon is stopped, so most of the burst overflows
+    kill -STOP $DAEMON_PID
+    exec 3>/dev/udp/127.0.0.1/15515
+    for i in $(seq 200); do
+        printf 'flood INFO message %d' "$i" >&3
+    done
+    kill -CONT $DAEMON_PID
+    sleep 0.2
+    # The kernel reports the drops along with the next datagram queued
+    printf 'flood INFO last' >&3
+    exec 3>&-
+    sleep 0.2
+
+    stored=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='flood' AND message != 'last';")
+    lost=$(sqlite3 "$STYLO_DB" "SELECT SUM(value) FROM logs JOIN log_fields ON log_id = logs.id WHERE source='stylo' AND key='lost' AND message LIKE '% messages lost on udp://127.0.0.1:15515 (receive buffer full)';")
+    run ./target/debug/stylo status
+
+    kill $DAEMON_PID
+    [ "$lost" -gt 0 ]
+    [ $((stored + lost)) -eq 200 ]
+    [[ "$output" == *"Messages lost, receive buffer full: $lost ($lost this boot)"* ]]
+    [[ "$output" == *"Entries dropped, queue full or write failed: 0 (0 this boot)"* ]]
+}
+
+@test "daemon: deleting the least severe, oldest entries over the size limit" {
+    printf 'max_db_size = 1M\nfull_text_search = off\n' > "$STYLO_CONF"
+    ./target/debug/stylo setup INFO "schema only"
+    sqlite3 "$STYLO_DB" "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 4000)
+        INSERT INTO logs (timestamp, source, severity, level, message)
+        SELECT CURRENT_TIMESTAMP, 'noisy', 'DEBUG', 7, 'debug ' || i || ' ' || hex(randomblob(150)) FROM n;
+        WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+        INSERT INTO logs (timestamp, source, severity, level, message)
+        SELECT CURRENT_TIMESTAMP, 'noisy', 'ERROR', 3, 'error ' || i || ' ' || hex(randomblob(150)) FROM n;"
+
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    echo "noisy INFO one more" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.5
+    kill $DAEMON_PID
+    wait $DAEMON_PID
+
+    used=$(sqlite3 "$STYLO_DB" "SELECT (page_count - freelist_count) * page_size FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size();")
+    errors=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE severity = 'ERROR';")
+    # The oldest DEBUG entries are gone, the newest are left
+    debug=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) > 0 AND COUNT(*) < 4000 AND MAX(message) LIKE 'debug 4000 %' FROM logs WHERE severity = 'DEBUG';")
+    report=$(sqlite3 "$STYLO_DB" "SELECT severity, message LIKE 'Deleted % entries, database over its size limit of 1.0 MiB (% DEBUG)', value FROM logs JOIN log_fields ON log_id = logs.id WHERE source = 'stylo' AND key = 'deleted';")
+    deleted=$(sqlite3 "$STYLO_DB" "SELECT 4000 - COUNT(*) FROM logs WHERE severity = 'DEBUG';")
+    # The total outlives the report
+    sqlite3 "$STYLO_DB" "DELETE FROM logs WHERE source = 'stylo';"
+    run ./target/debug/stylo status
+
+    [ "$used" -le 1048576 ]
+    [ "$errors" == "200" ]
+    [ "$debug" == "1" ]
+    [ "$report" == "WARNING|1|$deleted" ]
+    [[ "$output" == *"limit 1.0 MiB"* ]]
+    [[ "$output" == *"Entries deleted, size limit: $deleted ($deleted this boot)"* ]]
+}
+
+@test "status: keeping totals apart from the entries reporting them" {
+    echo "source_policy = off" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    echo '{"source":"stylo","severity":"WARNING","message":"9 messages lost","lost":9,"dropped":9,"deleted":9}' | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+    sqlite3 "$STYLO_DB" "INSERT INTO counters (name, boot, value) VALUES ('deleted', 0, 5);"
+
+    stored=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs JOIN log_fields ON log_id = logs.id WHERE source='stylo' AND key='lost';")
+    run ./target/debug/stylo status
+
+    [ "$stored" -eq 1 ]
+    [[ "$output" == *"Messages lost, receive buffer full: 0 (0 this boot)"* ]]
+    [[ "$output" == *"Entries dropped, queue full or write failed: 0 (0 this boot)"* ]]
+    [[ "$output" == *"Entries deleted, size limit: 5 (0 this boot)"* ]]
+}
+
+@test "daemon: importing kernel records without duplicates across restarts" {
+    printf '6,0,1000,-;Linux version 6.19\n' > "$STYLO_KMSG"
+    printf '3,1,2000,-;ata1: link down\n SUBSYSTEM=ata\n DEVICE=+ata:ata1\n' >> "$STYLO_KMSG"
+    printf '4,4,5000,-;oom-killer invoked\n' >> "$STYLO_KMSG"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+
+    printf '6,5,6000,-;eth0: link up\n' >> "$STYLO_KMSG"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    kill $DAEMON_PID
+
+    count=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='kernel' AND kmsg_seq IS NOT NULL;")
+    ata=$(sqlite3 "$STYLO_DB" "SELECT severity, facility, monotonic_usec, structured_data FROM logs WHERE kmsg_seq=1;")
+    lost=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='kernel' AND kmsg_seq IS NULL;")
+
+    [ "$count" -eq 4 ]
+    [ "$ata" == 'ERROR|kern|2000|[kmsg SUBSYSTEM="ata" DEVICE="+ata:ata1"]' ]
+    [ "$lost" == "2 kernel messages lost (ring buffer overrun)" ]
+}
+
+@test "daemon: saving input positions only along with their entries" {
+    rm -f test_tail.log
+    printf '6,0,1000,-;Linux version 6.19\n' > "$STYLO_KMSG"
+    echo one > test_tail.log
+    printf 'tail_file = test_tail.log vendor INFO\nbatch_latency_ms = 60000\n' > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    # Crash before the batch is committed
+    kill -9 $DAEMON_PID
+    wait $DAEMON_PID || true
+    lost=$(sqlite3 "$STYLO_DB" "SELECT (SELECT COUNT(*) FROM logs) + (SELECT COUNT(*) FROM kmsg_state) + (SELECT COUNT(*) FROM file_state);")
+
+    echo "tail_file = test_tail.log vendor INFO" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+    rm -f test_tail.log
+
+    kernel=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='kernel';")
+    vendor=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='vendor';")
+    state=$(sqlite3 "$STYLO_DB" "SELECT last_seq FROM kmsg_state; SELECT offset FROM file_state;" | tr '\n' ' ')
+    [ "$lost" -eq 0 ]
+    [ "$kernel" -eq 1 ]
+    [ "$vendor" -eq 1 ]
+    [ "$state" == "0 4 " ]
+}
+
+@test "daemon: retrying failed commits and recording the entries given up" {
+    rm -f test_tail.log
+    ./target/debug/stylo test_src INFO "create the database"
+    # Every batch carrying the file position fails to commit
+    sqlite3 "$STYLO_DB" "CREATE TRIGGER fail BEFORE INSERT ON file_state BEGIN SELECT RAISE(ABORT, 'disk full'); END;"
+    echo "tail_file = test_tail.log vendor INFO" > "$STYLO_CONF"
+    printf 'one\ntwo\n' > test_tail.log
+    ./target/debug/stylo -d 2> test_stderr.log &
+    DAEMON_PID=$!
+    sleep 5
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+    rm -f test_tail.log
+    retries=$(grep -c "retrying" test_stderr.log)
+    rm -f test_stderr.log
+
+    vendor=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM logs WHERE source='vendor';")
+    report=$(sqlite3 "$STYLO_DB" "SELECT l.message, f.value FROM logs l JOIN log_fields f ON f.log_id = l.id WHERE l.source='stylo' AND f.key='dropped';")
+    [ "$retries" -eq 4 ]
+    [ "$vendor" -eq 0 ]
+    [ "$report" == "Dropped 2 entries, database write failed (2 INFO)|2" ]
+}
+
+@test "daemon: recording single entries the database refuses" {
+    ./target/debug/stylo test_src INFO "create the database"
+    sqlite3 "$STYLO_DB" "CREATE TRIGGER refuse BEFORE INSERT ON logs WHEN NEW.source = 'poison' BEGIN SELECT RAISE(ABORT, 'refused'); END;"
+    ./target/debug/stylo -d 2> /dev/null &
+    DAEMON_PID=$!
+    sleep 0.2
+    for line in "poison ERROR first" "fine INFO kept" "poison WARNING second"; do
+        echo "$line" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    done
+    sleep 0.3
+    kill $DAEMON_PID
+    wait $DAEMON_PID || true
+
+    kept=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='fine';")
+    # Depending on the batches, in one report or two
+    dropped=$(sqlite3 "$STYLO_DB" "SELECT SUM(f.value), MIN(l.message LIKE 'Dropped % entries, database write failed (%)') FROM logs l JOIN log_fields f ON f.log_id = l.id WHERE l.source='stylo' AND f.key='dropped';")
+    run ./target/debug/stylo status
+    [ "$kept" == "kept" ]
+    [ "$dropped" == "2|1" ]
+    [[ "$output" == *"Entries dropped, queue full or write failed: 2 (2 this boot)"* ]]
+}
+
+@test "daemon: correcting early entries when the clock is stepped" {
+    [ "$(id -u)" -eq 0 ] || skip "setting the clock needs root"
+    ./target/debug/stylo -d &
+    DAEMON_PID=$!
+    sleep 0.2
+    unsynced=$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM boots WHERE clock_synced_usec IS NULL;")
+    if [ "$unsynced" -eq 0 ]; then
+        kill $DAEMON_PID
+        skip "clock is already synchronized"
+    fi
+
+    echo "early INFO before sync" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    # Step the clock to the time it already has
+    date -s "@$(date +%s.%N)" > /dev/null
+    sleep 0.2
+    echo "late INFO after sync" | socat - UNIX-SENDTO:"$STYLO_SOCK"
+    sleep 0.2
+    kill $DAEMON_PID
+
+    early=$(sqlite3 "$STYLO_DB" "SELECT unsynced, original_received_usec IS NOT NULL FROM logs WHERE source='early';")
+    late=$(sqlite3 "$STYLO_DB" "SELECT unsynced IS NULL FROM logs WHERE source='late';")
+    notice=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='stylo' AND severity='NOTICE';")
+    [ "$early" == "1|1" ]
+    [ "$late" == "1" ]
+    [[ "$notice" == "System clock stepped by "*", corrected 1 earlier entries" ]]
+}
+
+@test "boots: grouping entries by boot" {
+    export STYLO_BOOT_ID="test_boot_id"
+    echo "11111111-aaaa-4aaa-8aaa-111111111111" > "$STYLO_BOOT_ID"
+    ./target/debug/stylo app ERROR "before reboot"
+    ./target/debug/stylo app INFO "still running"
+    echo "22222222-bbbb-4bbb-8bbb-222222222222" > "$STYLO_BOOT_ID"
+    ./target/debug/stylo app ERROR "after reboot"
+
+    previous=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs JOIN boot_list b ON logs.boot = b.id WHERE b.offset = -1 AND level <= 3;")
+    run ./target/debug/stylo boots
+    rm -f "$STYLO_BOOT_ID"
+
+    [ "$previous" == "before reboot" ]
+    [ "${#lines[@]}" -eq 2 ]
+    [[ "${lines[0]}" == "  -1 11111111-aaaa-4aaa-8aaa-111111111111 "*"(2 entries)" ]]
+    [[ "${lines[1]}" == "   0 22222222-bbbb-4bbb-8bbb-222222222222 "*"(1 entries)" ]]
+}
+
+@test "wrap: capturing child output and forwarding its exit code" {
+    run ./target/debug/stylo wrap -s job --stderr WARNING -- sh -c 'echo first; echo oops >&2; echo second; exit 3'
+    [ "$status" -eq 3 ]
+
+    out=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='job' AND severity='INFO' ORDER BY id;" | tr '\n' ' ')
+    err=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='job' AND severity='WARNING';")
+    last=$(sqlite3 "$STYLO_DB" "SELECT severity, message FROM logs WHERE source='job' ORDER BY id DESC LIMIT 1;")
+    [ "$out" == "first second " ]
+    [ "$err" == "oops" ]
+    [ "$last" == "ERROR|sh exited with status 3" ]
+}
+
+@test "wrap: cutting off lines larger than max_message_size" {
+    echo "max_message_size = 100" > "$STYLO_CONF"
+    run ./target/debug/stylo wrap -s chatty -- python3 -c "print('x' * 200000); print('short')"
+    [ "$status" -eq 0 ]
+
+    long=$(sqlite3 "$STYLO_DB" "SELECT length(message), truncated, original_length FROM logs WHERE source='chatty' ORDER BY id LIMIT 1;")
+    short=$(sqlite3 "$STYLO_DB" "SELECT message, original_length IS NULL FROM logs WHERE source='chatty' ORDER BY id LIMIT 1 OFFSET 1;")
+    [ "$long" == "100|1|200000" ]
+    [ "$short" == "short|1" ]
+}
+
+@test "wrap: passing signals on to the child" {
+    ./target/debug/stylo wrap -s sleeper -- sh -c 'exec sleep 30' &
+    WRAP_PID=$!
+    sleep 0.3
+    kill -TERM $WRAP_PID
+    status=0
+    wait $WRAP_PID || status=$?
+
+    ./target/debug/stylo wrap -s trapper -- sh -c 'trap "echo reloading" HUP; trap "echo stopping; exit 4" TERM; while :; do sleep 0.1; done' &
+    WRAP_PID=$!
+    sleep 0.3
+    kill -HUP $WRAP_PID
+    sleep 0.3
+    kill -TERM $WRAP_PID
+    trapped=0
+    wait $WRAP_PID || trapped=$?
+
+    sleeper=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='sleeper';")
+    trapper=$(sqlite3 "$STYLO_DB" "SELECT message FROM logs WHERE source='trapper' ORDER BY id;" | tr '\n' ' ')
+    [ "$status" -eq 143 ]
+    [ "$sleeper" == "sh killed by signal 15" ]
+    [ "$trapped" -eq 4 ]
+    [ "$trapper" == "reloading stopping sh exited with status 4 " ]
+}
+
+@test "container-log: tagging container output and lifecycle" {
+    run ./target/debug/stylo container-log web -- sh -c 'echo ready; echo failed >&2; exit 2'
+    [ "$status" -eq 2 ]
+
+    lines=$(sqlite3 "$STYLO_DB" "SELECT source, stream, message FROM logs WHERE container='web' AND stream IS NOT NULL ORDER BY stream;" | tr '\n' ' ')
+    start=$(sqlite3 "$STYLO_DB" "SELECT l.message FROM logs l JOIN log_fields f ON f.log_id=l.id WHERE l.container='web' AND f.key='event' AND f.value='start';")
+    code=$(sqlite3 "$STYLO_DB" "SELECT f.value FROM logs l JOIN log_fields f ON f.log_id=l.id WHERE l.container='web' AND f.key='exit_code';")
+    [ "$lines" == "web|stderr|failed web|stdout|ready " ]
+    [ "$start" == "container web started" ]
+    [ "$code" -eq 2 ]
+}
+
+@test "indexes: looking up time ranges and sources without scanning" {
+    run ./target/debug/stylo test_src INFO "indexed"
+    [ "$status" -eq 0 ]
+
+    # The lower id bound comes from the timestamp index alone
+    plan=$(sqlite3 "$STYLO_DB" "EXPLAIN QUERY PLAN SELECT MIN(id) FROM logs INDEXED BY logs_timestamp WHERE timestamp >= datetime('now', '-1 hour');")
+    [[ "$plan" == *"USING COVERING INDEX logs_timestamp"* ]]
+
+    plan=$(sqlite3 "$STYLO_DB" "EXPLAIN QUERY PLAN SELECT message FROM logs WHERE source = 'test_src' AND id >= 1;")
+    [[ "$plan" == *"USING INDEX logs_source (source=? AND id>?)"* ]]
+
+    # Retention goes by receive time, falling back to the timestamp
+    plan=$(sqlite3 "$STYLO_DB" "EXPLAIN QUERY PLAN DELETE FROM logs WHERE received_usec < 0 OR received_usec IS NULL AND timestamp < datetime('now', '-24 hours');")
+    [[ "$plan" != *"SCAN logs"* ]]
+}
+
+@test "search: finding messages by phrase, prefix and boolean queries" {
+    ./target/debug/stylo charon ERROR "upstream timeout after 3000 ms"
+    ./target/debug/stylo charon INFO "upstream answered in 12 ms"
+    ./target/debug/stylo sshd WARNING "connection refused by peer"
+    run ./target/debug/stylo search timeout
+    [[ "$output" == *"Full-text search is off"* ]]
+
+    # The daemon builds the index from the existing entries
+    ./target/debug/stylo -d &
+    pid=$!
+    sleep 0.5
+    kill $pid
+    wait $pid || true
+    ./target/debug/stylo charon DEBUG "retrying upstream after timeout"
+
+    run ./target/debug/stylo search '"upstream timeout"'
+    [ "$status" -eq 0 ]
+    [ "${#lines[@]}" -eq 1 ]
+    [[ "${lines[0]}" == *"charon ERROR: [upstream timeout] after 3000 ms" ]]
+
+    run ./target/debug/stylo search "refus*" OR answered
+    [ "${#lines[@]}" -eq 2 ]
+
+    run ./target/debug/stylo search upstream NOT timeout
+    [ "${#lines[@]}" -eq 1 ]
+    [[ "${lines[0]}" == *"[upstream] answered in 12 ms" ]]
+
+    # Retention deletes leave no stale matches behind
+    sqlite3 "$STYLO_DB" "DELETE FROM logs WHERE severity = 'DEBUG';"
+    run ./target/debug/stylo search retrying
+    [ "$status" -eq 0 ]
+    [ "${#lines[@]}" -eq 0 ]
+
+    run ./target/debug/stylo search '"unterminated'
+    [ "$status" -ne 0 ]
+
+    echo "full_text_search = off" > "$STYLO_CONF"
+    ./target/debug/stylo -d &
+    pid=$!
+    sleep 0.5
+    kill $pid
+    wait $pid || true
+    [ "$(sqlite3 "$STYLO_DB" "SELECT COUNT(*) FROM sqlite_master WHERE name LIKE 'logs_fts%';")" -eq 0 ]
+}
+
+@test "bench: comparing single and batched socket reception" {
+    run ./target/debug/stylo bench 2000
+    [ "$status" -eq 0 ]
+    [[ "${lines[1]}" == *"1 per call:"*"msg/s" ]]
+    [[ "${lines[2]}" == *"up to 32 per call:"*"msg/s ("*"x)" ]]
+}
+
+@test "bench: comparing per-entry and batched database writes" {
+    echo "batch_size = 50" > "$STYLO_CONF"
+    run ./target/debug/stylo bench --write 200
+    [ "$status" -eq 0 ]
+    [ "${lines[0]}" == "Writing 200 entries per run (durability full)" ]
+    [[ "${lines[1]}" == *"1 per commit:"*"entries/s" ]]
+    [[ "${lines[2]}" == *"50 per commit:"*"entries/s ("*"x)" ]]
+    # The scratch database is gone, the real one untouched
+    [ ! -e "$STYLO_DB.bench" ]
+    [ ! -e "$STYLO_DB" ]
+}
+
+@test "migrate: upgrading an old database and refusing newer ones" {
+    sqlite3 "$STYLO_DB" "CREATE TABLE logs (id INTEGER PRIMARY KEY, timestamp DATETIME, source TEXT, severity TEXT, message TEXT);"
+    sqlite3 "$STYLO_DB" "INSERT INTO logs (timestamp, source, severity, message) VALUES (datetime('now'), 'old_src', 'warn', 'old_msg');"
+
+    run ./target/debug/stylo migrate --dry-run
+    [ "$status" -eq 0 ]
+    [[ "$output" == *"schema version 0"* ]]
+    [[ "$output" == *"would apply 1: Baseline"* ]]
+    [ "$(sqlite3 "$STYLO_DB" "PRAGMA user_version;")" -eq 0 ]
+
+    run ./target/debug/stylo migrate
+    [ "$status" -eq 0 ]
+    [ "$(sqlite3 "$STYLO_DB" "PRAGMA user_version;")" -ge 1 ]
+    result=$(sqlite3 "$STYLO_DB" "SELECT severity, level FROM logs WHERE source='old_src';")
+    [ "$result" == "WARNING|4" ]
+
+    sqlite3 "$STYLO_DB" "PRAGMA user_version = 999;"
+    run ./target/debug/stylo test_src INFO "too new"
+    [ "$status" -ne 0 ]
+    [[ "$output" == *"schema version 999"* ]]
+}
+
+@test "retention: applying per-source and per-severity rules" {
+    run ./target/debug/stylo retention
+    [[ "$output" == *"default"*"1d"* ]]
+
+    ./target/debug/stylo retention add --severity ERROR+ 90d
+    ./target/debug/stylo retention add --source charon --severity DEBUG 1h
+    run ./target/debug/stylo retention add 7d
+    [ "$output" == "" ]
+    run ./target/debug/stylo retention
+    [ "${#lines[@]}" -eq 3 ]
+    [ "${lines[0]}" == "  3  source=charon DEBUG              1h" ]
+    [ "${lines[1]}" == "  2  ERROR and above                  90d" ]
+    [ "${lines[2]}" == "  1  default                          7d" ]
+
+    sqlite3 "$STYLO_DB" "INSERT INTO logs (timestamp, source, severity, level, message) VALUES
+        (datetime('now', '-2 hours'), 'charon', 'DEBUG', 7, 'old charon debug'),
+        (datetime('now', '-10 minutes'), 'charon', 'DEBUG', 7, 'new charon debug'),
+        (datetime('now', '-30 days'), 'charon', 'ERROR', 3, 'charon error'),
+        (datetime('now', '-100 days'), 'kernel', 'CRIT', 2, 'ancient crit'),
+        (datetime('now', '-2 days'), 'pluto', 'DEBUG', 7, 'pluto debug'),
+        (datetime('now', '-8 days'), 'pluto', 'INFO', 6, 'old pluto info'),
+        (datetime('now', '-8 days'), 'pluto', 'CUSTOM', NULL, 'old unknown');"
+    # Age counts from receipt, whatever time the producer claimed
+    sqlite3 "$STYLO_DB" "INSERT INTO logs (timestamp, received_usec, source, severity, level, message) VALUES
+        (datetime('now', '+1 year'), (unixepoch() - 8 * 86400) * 1000000, 'pluto', 'INFO', 6, 'future pluto info'),
+        ('1970-01-01 00:00:00', unixepoch() * 1000000, 'pluto', 'INFO', 6, 'fresh pluto info');"
+
+    run ./target/debug/stylo -c
+    [ "$status" -eq 0 ]
+    [[ "$output" == *"Deleted 1 entries under rule "*" (source=charon DEBUG, 1h)."* ]]
+    [[ "$output" == *"Deleted 1 entries under rule "*" (ERROR and above, 90d)."* ]]
+    [[ "$output" == *"Deleted 3 entries under rule "*" (default, 7d)."* ]]
+
+    kept=$(sqlite3 "$STYLO_DB" "SELECT group_concat(message, ',') FROM (SELECT message FROM logs ORDER BY id);")
+    [ "$kept" == "new charon debug,charon error,pluto debug,fresh pluto info" ]
+
+    id=$(sqlite3 "$STYLO_DB" "SELECT id FROM retention_rules WHERE source = 'charon';")
+    ./target/debug/stylo retention remove "$id"
+    run ./target/debug/stylo retention remove "$id"
+    [ "$status" -eq 1 ]
+    [ "$output" == "No retention rule $id" ]
+    run ./target/debug/stylo retention add --severity LOUD 1d
+    [ "$status" -eq 1 ]
+    [ "$output" == "Invalid severity: LOUD" ]
+}
+
 @test "compact: cleaning old entries" {
     # Insert an old entry manually
     sqlite3 "$STYLO_DB" "CREATE TABLE IF NOT EXISTS logs (id INTEGER PRIMARY KEY, timestamp DATETIME, source TEXT, severity TEXT, message TEXT);"